@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate game;
+
+use game::channel::ChannelEndpoint;
+use game::packets::Packet;
+
+// everything a client sends goes through the channel and then the packet decoder, neither may
+// panic whatever the bytes are
+fuzz_target!(|data: &[u8]| {
+    let _ = Packet::decode(data);
+
+    let mut channel = ChannelEndpoint::new();
+    if let Ok(messages) = channel.receive(data) {
+        for message in messages {
+            let _ = Packet::decode(&message);
+        }
+    }
+});
@@ -0,0 +1,370 @@
+//! `#[derive(NetComponent)]` for the game's replicated components.
+//!
+//! Every field is bit packed in declaration order through `net::NetField`, unless it's marked
+//! with one of the `net` attributes:
+//!
+//! - `#[net(skip)]` leaves the field out, it keeps its local value when state is loaded.
+//! - `#[net(quantize = "<min>, <max>, <bits>")]` packs the field through `net::NetQuantize`,
+//!   rounded to `bits` bits within `[min, max]`.
+//! - `#[net(quantize = "angle, <bits>")]` packs an `f32` angle in radians to `bits` bits.
+//! - `#[net(default = <value>)]` packs a flag telling whether the field differs from the value,
+//!   followed by the field only if it does. Combines with `quantize`.
+//!
+//! The bounds, bit counts and defaults are expressions, so constants in scope of the struct can
+//! be used. A default can also be given as a string holding an expression.
+//!
+//! Deltas start with a flag per field telling whether it differs from the baseline, followed by
+//! the values of only the fields that do.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use syn::punctuated::Punctuated;
+use syn::{Data, DeriveInput, Expr, ExprLit, Fields, Lit, Member, Meta, NestedMeta};
+
+enum Encoding {
+    Plain,
+    Skip,
+    Quantize {
+        min: Box<Expr>,
+        max: Box<Expr>,
+        bits: Box<Expr>,
+    },
+    Angle {
+        bits: Box<Expr>,
+    },
+}
+
+/// A field's `net` attributes.
+struct NetAttributes {
+    encoding: Encoding,
+    // value the field is packed as a single flag for
+    default: Option<Expr>,
+}
+
+#[proc_macro_derive(NetComponent, attributes(net))]
+pub fn derive_net_component(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, syn::Error> {
+    let fields = match input.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "NetComponent can only be derived for structs",
+            ))
+        }
+    };
+
+    let members: Vec<(Member, &syn::Field)> = match *fields {
+        Fields::Named(ref fields) => fields
+            .named
+            .iter()
+            .map(|f| (Member::Named(f.ident.clone().unwrap()), f))
+            .collect(),
+        Fields::Unnamed(ref fields) => fields
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (Member::Unnamed(i.into()), f))
+            .collect(),
+        Fields::Unit => Vec::new(),
+    };
+
+    let mut stores = Vec::new();
+    let mut loads = Vec::new();
+    let mut delta_fields = Vec::new();
+    let mut delta_writes = Vec::new();
+    let mut delta_flag_reads = Vec::new();
+    let mut delta_reads = Vec::new();
+    let mut delta_skips = Vec::new();
+    let mut schema = Vec::new();
+    for (i, (member, field)) in members.into_iter().enumerate() {
+        let attributes = field_attributes(field)?;
+        if let Encoding::Skip = attributes.encoding {
+            continue;
+        }
+
+        let ty = &field.ty;
+        schema.push(describe_field(&member, ty, &attributes));
+
+        let writer = Ident::new("writer", Span::call_site());
+        let reader = Ident::new("reader", Span::call_site());
+        let baseline = Ident::new("baseline", Span::call_site());
+        let previous = Ident::new("previous", Span::call_site());
+        let current = Ident::new(&format!("field_{}", i), Span::call_site());
+        let changed = Ident::new(&format!("changed_{}", i), Span::call_site());
+
+        let store = write_field(&attributes, quote!(self.#member), &writer);
+        let load = read_field(&attributes, ty, quote!(&mut #reader));
+        stores.push(store);
+        loads.push(quote!(self.#member = #load;));
+
+        // each field is compared to the baseline by its encoding, so changes too small to
+        // survive quantization don't count
+        let store_current = write_field(&attributes, quote!(self.#member), &current);
+        let read_previous = read_field(&attributes, ty, quote!(&mut #baseline));
+        let store_previous = write_field(&attributes, quote!(value), &previous);
+        delta_fields.push(quote! {
+            let mut #current = ::bits::BitWriter::new();
+            #store_current
+            let #changed = match baseline {
+                Some(ref mut reader) => {
+                    let mut #baseline = reader;
+                    let value: #ty = #read_previous;
+                    let mut #previous = ::bits::BitWriter::new();
+                    #store_previous
+                    #previous != #current
+                }
+                None => true,
+            };
+            writer.write_bool(#changed);
+        });
+        delta_writes.push(quote! {
+            if #changed {
+                writer.append(&#current);
+            }
+        });
+        // all the changed flags come first, followed by the values of the changed fields
+        delta_flag_reads.push(quote!(let #changed = reader.read_bool()?;));
+        delta_reads.push(quote! {
+            if #changed {
+                self.#member = #load;
+            }
+        });
+        let skip = read_field(&attributes, ty, quote!(reader));
+        delta_skips.push(quote! {
+            if #changed {
+                let _: #ty = #skip;
+            }
+        });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    // the flags are read both when applying and when skipping a delta
+    let delta_flag_reads = &delta_flag_reads;
+
+    Ok(quote! {
+        impl #impl_generics ::net::NetComponent for #name #ty_generics #where_clause {
+            fn net_schema() -> String {
+                let fields: Vec<String> = vec![#(#schema),*];
+                fields.join(", ")
+            }
+
+            fn net_store(&self) -> Result<Vec<u8>, ::failure::Error> {
+                let mut writer = ::bits::BitWriter::new();
+                #(#stores)*
+                Ok(writer.into_bytes())
+            }
+            fn net_load(&mut self, data: &[u8]) -> Result<(), ::failure::Error> {
+                let mut reader = ::bits::BitReader::new(data);
+                #(#loads)*
+                Ok(())
+            }
+
+            fn read_delta(
+                &self,
+                baseline: Option<&[u8]>,
+            ) -> Result<::bits::BitWriter, ::failure::Error> {
+                let mut baseline = baseline.map(::bits::BitReader::new);
+                let mut writer = ::bits::BitWriter::new();
+                #(#delta_fields)*
+                #(#delta_writes)*
+                Ok(writer)
+            }
+            fn write_delta(&mut self, data: &[u8]) -> Result<(), ::failure::Error> {
+                let mut reader = ::bits::BitReader::new(data);
+                #(#delta_flag_reads)*
+                #(#delta_reads)*
+                Ok(())
+            }
+            fn skip_delta(reader: &mut ::bits::BitReader) -> Result<(), ::failure::Error> {
+                #(#delta_flag_reads)*
+                #(#delta_skips)*
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Expression giving the text describing how a field is encoded, for
+/// `NetComponent::net_schema`. Bounds, bit counts and defaults are described by their values
+/// rather than the expressions giving them, so changing a constant changes the schema.
+fn describe_field(member: &Member, ty: &syn::Type, attributes: &NetAttributes) -> TokenStream2 {
+    let mut format = format!("{}: {} ", quote!(#member), quote!(#ty))
+        .replace("{", "{{")
+        .replace("}", "}}");
+    let mut values = Vec::new();
+    match attributes.encoding {
+        Encoding::Plain => format.push_str("plain"),
+        Encoding::Quantize {
+            ref min,
+            ref max,
+            ref bits,
+        } => {
+            format.push_str("quantize({:?}, {:?}, {:?})");
+            values.extend(vec![quote!(#min), quote!(#max), quote!(#bits)]);
+        }
+        Encoding::Angle { ref bits } => {
+            format.push_str("angle({:?})");
+            values.push(quote!(#bits));
+        }
+        Encoding::Skip => format.push_str("skip"),
+    }
+    if let Some(ref default) = attributes.default {
+        format.push_str(" default({:?})");
+        values.push(quote!(#default));
+    }
+    quote!(format!(#format, #(#values),*))
+}
+
+/// Code writing `value` to the `BitWriter` named `writer` with the field's encoding.
+fn write_field(attributes: &NetAttributes, value: TokenStream2, writer: &Ident) -> TokenStream2 {
+    let write = match attributes.encoding {
+        Encoding::Plain => quote! {
+            ::net::NetField::write(&#value, &mut #writer);
+        },
+        Encoding::Quantize {
+            ref min,
+            ref max,
+            ref bits,
+        } => quote! {
+            ::net::NetQuantize::write_quantized(&#value, &mut #writer, #min, #max, #bits);
+        },
+        Encoding::Angle { ref bits } => quote! {
+            #writer.write_angle(#value, #bits);
+        },
+        Encoding::Skip => quote!(),
+    };
+    match attributes.default {
+        Some(ref default) => quote! {
+            #writer.write_bool(#value != #default);
+            if #value != #default {
+                #write
+            }
+        },
+        None => write,
+    }
+}
+
+/// Expression reading a value of the field's type through `reader`, an expression giving a
+/// `&mut BitReader`.
+fn read_field(attributes: &NetAttributes, ty: &syn::Type, reader: TokenStream2) -> TokenStream2 {
+    let read = match attributes.encoding {
+        Encoding::Plain => quote! {
+            <#ty as ::net::NetField>::read(#reader)?
+        },
+        Encoding::Quantize {
+            ref min,
+            ref max,
+            ref bits,
+        } => quote! {
+            <#ty as ::net::NetQuantize>::read_quantized(#reader, #min, #max, #bits)?
+        },
+        Encoding::Angle { ref bits } => quote! {
+            (#reader).read_angle(#bits)?
+        },
+        Encoding::Skip => quote!(),
+    };
+    match attributes.default {
+        Some(ref default) => quote! {
+            if (#reader).read_bool()? {
+                #read
+            } else {
+                #default
+            }
+        },
+        None => read,
+    }
+}
+
+fn field_attributes(field: &syn::Field) -> Result<NetAttributes, syn::Error> {
+    let mut attributes = NetAttributes {
+        encoding: Encoding::Plain,
+        default: None,
+    };
+    for attr in field.attrs.iter() {
+        if attr.path.segments.len() != 1 || attr.path.segments[0].ident != "net" {
+            continue;
+        }
+
+        let nested = match attr.parse_meta()? {
+            Meta::List(list) => list.nested,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[net(...)]")),
+        };
+        for meta in nested {
+            match meta {
+                NestedMeta::Meta(Meta::Word(ref word)) if word == "skip" => {
+                    attributes.encoding = Encoding::Skip;
+                }
+                NestedMeta::Meta(Meta::NameValue(ref name_value))
+                    if name_value.ident == "quantize" =>
+                {
+                    attributes.encoding = match name_value.lit {
+                        Lit::Str(ref settings) => parse_quantize(settings)?,
+                        ref lit => {
+                            return Err(syn::Error::new_spanned(
+                                lit,
+                                "expected quantize = \"<min>, <max>, <bits>\" or \
+                                 quantize = \"angle, <bits>\"",
+                            ))
+                        }
+                    };
+                }
+                NestedMeta::Meta(Meta::NameValue(ref name_value))
+                    if name_value.ident == "default" =>
+                {
+                    attributes.default = Some(match name_value.lit {
+                        Lit::Str(ref value) => value.parse()?,
+                        ref lit => Expr::Lit(ExprLit {
+                            attrs: Vec::new(),
+                            lit: lit.clone(),
+                        }),
+                    });
+                }
+                meta => return Err(syn::Error::new_spanned(meta, "unknown net attribute")),
+            }
+        }
+    }
+    Ok(attributes)
+}
+
+fn parse_quantize(settings: &syn::LitStr) -> Result<Encoding, syn::Error> {
+    let parts: Punctuated<Expr, Token![,]> = settings.parse_with(Punctuated::parse_terminated)?;
+    let mut parts: Vec<Expr> = parts.into_iter().collect();
+
+    let is_angle = match parts.first() {
+        Some(Expr::Path(path)) => {
+            path.path.segments.len() == 1 && path.path.segments[0].ident == "angle"
+        }
+        _ => false,
+    };
+    match (is_angle, parts.len()) {
+        (true, 2) => Ok(Encoding::Angle {
+            bits: Box::new(parts.pop().unwrap()),
+        }),
+        (false, 3) => {
+            let bits = Box::new(parts.pop().unwrap());
+            let max = Box::new(parts.pop().unwrap());
+            let min = Box::new(parts.pop().unwrap());
+            Ok(Encoding::Quantize { min, max, bits })
+        }
+        _ => Err(syn::Error::new_spanned(
+            settings,
+            "expected \"<min>, <max>, <bits>\" or \"angle, <bits>\"",
+        )),
+    }
+}
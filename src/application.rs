@@ -0,0 +1,22 @@
+use failure::Error;
+
+use embla::input::Input;
+
+use client_application::ClientApplication;
+use client_server_application::ClientServerApplication;
+
+pub trait Application {
+    fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error>;
+}
+
+impl Application for ClientServerApplication {
+    fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error> {
+        ClientServerApplication::update(self, dt, input)
+    }
+}
+
+impl Application for ClientApplication {
+    fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error> {
+        ClientApplication::update(self, dt, input)
+    }
+}
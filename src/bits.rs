@@ -0,0 +1,221 @@
+use failure::Error;
+use std::f64::consts::PI;
+
+use net::ProtocolError;
+
+/// Packs values of any bit width tightly into bytes, most significant bit first.
+#[derive(Clone, PartialEq)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            data: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Writes the lowest `bits` bits of the value, at most 32.
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        for i in (0..bits).rev() {
+            if self.len % 8 == 0 {
+                self.data.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                let last = self.data.len() - 1;
+                self.data[last] |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(value.to_bits(), 32);
+    }
+
+    /// Writes a value in `[min, max]` rounded to one of `2^bits` evenly spaced steps, values
+    /// outside the range are clamped.
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, bits: u32) {
+        let steps = ((1u64 << bits) - 1) as f64;
+        let value = (value.max(min).min(max) - min) as f64 / (max - min) as f64;
+        self.write_bits((value * steps).round() as u32, bits);
+    }
+
+    /// Writes an angle in radians to `bits` bits of precision, wrapped to a single turn.
+    pub fn write_angle(&mut self, value: f32, bits: u32) {
+        let steps = (1u64 << bits) as f64;
+        let turns = value as f64 / (2.0 * PI);
+        let turn = turns - turns.floor();
+        self.write_bits(((turn * steps).round() as u64 % (1u64 << bits)) as u32, bits);
+    }
+
+    /// Writes everything written to another writer so far.
+    pub fn append(&mut self, other: &BitWriter) {
+        for position in 0..other.len {
+            let bit = (other.data[position / 8] >> (7 - position % 8)) & 1;
+            self.write_bits(bit as u32, 1);
+        }
+    }
+
+    /// Copies the next `bits` bits of a reader.
+    pub fn copy_from(&mut self, reader: &mut BitReader, bits: usize) -> Result<(), Error> {
+        for _ in 0..bits {
+            let bit = reader.read_bits(1)?;
+            self.write_bits(bit, 1);
+        }
+        Ok(())
+    }
+
+    /// Number of bits written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads values written by a `BitWriter`, in the same order and widths.
+#[derive(Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    /// Number of bits read so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, Error> {
+        debug_assert!(bits <= 32);
+        if self.position + bits as usize > self.data.len() * 8 {
+            return Err(ProtocolError::Truncated.into());
+        }
+
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = value << 1 | bit as u32;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_bits(self.read_bits(32)?))
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, bits: u32) -> Result<f32, Error> {
+        let steps = ((1u64 << bits) - 1) as f64;
+        let value = self.read_bits(bits)? as f64 / steps;
+        Ok(min + (value * (max - min) as f64) as f32)
+    }
+
+    /// Reads an angle written by `write_angle`, in `[0, 2π)`.
+    pub fn read_angle(&mut self, bits: u32) -> Result<f32, Error> {
+        let steps = (1u64 << bits) as f64;
+        Ok((self.read_bits(bits)? as f64 / steps * 2.0 * PI) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_of_mixed_widths_round_trip() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(5, 3);
+        writer.write_bool(true);
+        writer.write_bits(0xdead_beef, 32);
+        writer.write_f32(-1.25);
+        assert_eq!(writer.len(), 68);
+
+        let data = writer.into_bytes();
+        assert_eq!(data.len(), 9);
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_bits(3).unwrap(), 5);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_bits(32).unwrap(), 0xdead_beef);
+        assert_eq!(reader.read_f32().unwrap(), -1.25);
+    }
+
+    #[test]
+    fn quantized_values_are_rounded_and_clamped() {
+        let mut writer = BitWriter::new();
+        writer.write_quantized(0.3, -1.0, 1.0, 8);
+        writer.write_quantized(5.0, -1.0, 1.0, 8);
+        writer.write_quantized(-5.0, -1.0, 1.0, 8);
+
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        assert!((reader.read_quantized(-1.0, 1.0, 8).unwrap() - 0.3).abs() <= 1.0 / 255.0);
+        assert_eq!(reader.read_quantized(-1.0, 1.0, 8).unwrap(), 1.0);
+        assert_eq!(reader.read_quantized(-1.0, 1.0, 8).unwrap(), -1.0);
+    }
+
+    #[test]
+    fn angles_are_wrapped_to_a_single_turn() {
+        let mut writer = BitWriter::new();
+        writer.write_angle(-PI as f32 / 2.0, 10);
+        writer.write_angle(2.0 * PI as f32, 10);
+
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        let quarter = reader.read_angle(10).unwrap();
+        assert!((quarter - 1.5 * PI as f32).abs() < 0.01);
+        assert_eq!(reader.read_angle(10).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn appended_and_copied_bits_are_unchanged() {
+        let mut first = BitWriter::new();
+        first.write_bits(0b101, 3);
+        let mut second = BitWriter::new();
+        second.write_bits(0b1_1001, 5);
+        first.append(&second);
+        assert_eq!(first.len(), 8);
+
+        let data = first.clone().into_bytes();
+        let mut reader = BitReader::new(&data);
+        let mut copy = BitWriter::new();
+        copy.copy_from(&mut reader, 8).unwrap();
+        assert!(copy == first);
+        assert_eq!(copy.into_bytes(), vec![0b1011_1001]);
+    }
+
+    #[test]
+    fn reading_past_the_end_is_an_error() {
+        let mut reader = BitReader::new(&[0xff]);
+        assert_eq!(reader.read_bits(6).unwrap(), 0b11_1111);
+        let error = reader.read_bits(3).unwrap_err();
+        assert!(error.downcast_ref::<ProtocolError>().is_some());
+    }
+}
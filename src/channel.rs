@@ -0,0 +1,356 @@
+use bincode;
+use failure::Error;
+use std::collections::BTreeMap;
+
+use net::ProtocolError;
+
+/// How a message is delivered. Reliable messages arrive exactly once and in the order they were
+/// sent, unreliable messages may be lost, duplicated or arrive out of order.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Reliable,
+    Unreliable,
+}
+
+type MessageId = u32;
+
+// how long to wait for an ack before sending a reliable message again
+static RESEND_INTERVAL: f64 = 0.1;
+// a reliable message sent this many times without an ack means the peer is gone
+static MAX_SENDS: u32 = 50;
+// datagrams are filled up to this many bytes, to stay below common MTUs
+static MAX_DATAGRAM_SIZE: usize = 1200;
+// encoded sizes of the parts of a datagram, as bincode writes them
+static DATAGRAM_HEADER_SIZE: usize = 24;
+static ACK_SIZE: usize = 4;
+static RELIABLE_HEADER_SIZE: usize = 12;
+static UNRELIABLE_HEADER_SIZE: usize = 8;
+// how far ahead of the next expected reliable message others are buffered, anything further out
+// can't come from a well behaved peer and is dropped unacked
+static RECEIVE_WINDOW: MessageId = 1024;
+// a peer leaving this many bytes of reliable messages unacked can't keep up and is given up on
+static MAX_UNACKED_BYTES: usize = 1 << 20;
+
+/// Everything sent to a peer in one flush, the unit handed to the transport.
+#[derive(Serialize, Deserialize)]
+struct Datagram {
+    acks: Vec<MessageId>,
+    reliable: Vec<(MessageId, Vec<u8>)>,
+    unreliable: Vec<Vec<u8>>,
+}
+
+/// Datagrams being filled by a flush, with the encoded size of each.
+struct Datagrams(Vec<(usize, Datagram)>);
+
+impl Datagrams {
+    /// The datagram to add a part of the given encoded size to, a new one if it doesn't fit in
+    /// the last. Parts larger than a datagram get one of their own.
+    fn fitting(&mut self, size: usize) -> &mut Datagram {
+        let full = match self.0.last() {
+            Some(&(used, _)) => used > DATAGRAM_HEADER_SIZE && used + size > MAX_DATAGRAM_SIZE,
+            None => true,
+        };
+        if full {
+            let datagram = Datagram {
+                acks: Vec::new(),
+                reliable: Vec::new(),
+                unreliable: Vec::new(),
+            };
+            self.0.push((DATAGRAM_HEADER_SIZE, datagram));
+        }
+
+        let last = self.0.last_mut().unwrap();
+        last.0 += size;
+        &mut last.1
+    }
+}
+
+struct Unacked {
+    data: Vec<u8>,
+    last_sent: Option<f64>,
+    sends: u32,
+}
+
+/// One end of a connection's channels. Messages are queued with `send`, bundled into datagrams
+/// by `flush` and unpacked by the other end's `receive`.
+pub struct ChannelEndpoint {
+    next_send_id: MessageId,
+    unacked: BTreeMap<MessageId, Unacked>,
+    unacked_bytes: usize,
+    unreliable: Vec<Vec<u8>>,
+    lost: bool,
+
+    next_receive_id: MessageId,
+    // reliable messages that arrived ahead of one still missing
+    received: BTreeMap<MessageId, Vec<u8>>,
+    pending_acks: Vec<MessageId>,
+}
+
+impl ChannelEndpoint {
+    pub fn new() -> ChannelEndpoint {
+        ChannelEndpoint {
+            next_send_id: 0,
+            unacked: BTreeMap::new(),
+            unacked_bytes: 0,
+            unreliable: Vec::new(),
+            lost: false,
+
+            next_receive_id: 0,
+            received: BTreeMap::new(),
+            pending_acks: Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, channel: Channel, data: Vec<u8>) {
+        match channel {
+            Channel::Reliable => {
+                let id = self.next_send_id;
+                self.next_send_id += 1;
+                self.unacked_bytes += data.len();
+                if self.unacked_bytes > MAX_UNACKED_BYTES {
+                    self.lost = true;
+                }
+                self.unacked.insert(
+                    id,
+                    Unacked {
+                        data,
+                        last_sent: None,
+                        sends: 0,
+                    },
+                );
+            }
+            Channel::Unreliable => self.unreliable.push(data),
+        }
+    }
+
+    /// Unpacks a datagram from the other end, returning the messages that are ready to be
+    /// delivered in order.
+    pub fn receive(&mut self, datagram: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let datagram: Datagram = bincode::deserialize(datagram)
+            .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+
+        for id in datagram.acks {
+            if let Some(unacked) = self.unacked.remove(&id) {
+                self.unacked_bytes -= unacked.data.len();
+            }
+        }
+
+        let mut messages = Vec::new();
+        for (id, data) in datagram.reliable {
+            if id >= self.next_receive_id.saturating_add(RECEIVE_WINDOW) {
+                continue;
+            }
+            // always ack, the previous ack may have been lost
+            self.pending_acks.push(id);
+            if id >= self.next_receive_id {
+                self.received.insert(id, data);
+            }
+        }
+        while let Some(data) = self.received.remove(&self.next_receive_id) {
+            messages.push(data);
+            self.next_receive_id += 1;
+        }
+
+        messages.extend(datagram.unreliable);
+
+        Ok(messages)
+    }
+
+    /// Bundles queued messages, acks and reliable messages due for a resend into datagrams of
+    /// at most `MAX_DATAGRAM_SIZE` bytes, unless a single message is larger. Returns nothing if
+    /// there is nothing to send.
+    pub fn flush(&mut self, time: f64) -> Result<Vec<Vec<u8>>, Error> {
+        let mut datagrams = Datagrams(Vec::new());
+        for id in self.pending_acks.drain(..) {
+            datagrams.fitting(ACK_SIZE).acks.push(id);
+        }
+        for (id, unacked) in self.unacked.iter_mut() {
+            let due = unacked
+                .last_sent
+                .map(|t| time - t >= RESEND_INTERVAL)
+                .unwrap_or(true);
+            if !due {
+                continue;
+            }
+            if unacked.sends >= MAX_SENDS {
+                self.lost = true;
+            }
+
+            let size = RELIABLE_HEADER_SIZE + unacked.data.len();
+            datagrams
+                .fitting(size)
+                .reliable
+                .push((*id, unacked.data.clone()));
+            unacked.last_sent = Some(time);
+            unacked.sends += 1;
+        }
+        for data in self.unreliable.drain(..) {
+            let size = UNRELIABLE_HEADER_SIZE + data.len();
+            datagrams.fitting(size).unreliable.push(data);
+        }
+
+        let mut encoded = Vec::with_capacity(datagrams.0.len());
+        for (_, datagram) in datagrams.0 {
+            encoded.push(bincode::serialize(&datagram)?);
+        }
+        Ok(encoded)
+    }
+
+    /// Whether the other end has stopped acknowledging reliable messages, or is acknowledging
+    /// them too slowly to keep up, after which the connection should be dropped.
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers every datagram `from` flushes at the given time to `to`.
+    fn deliver(from: &mut ChannelEndpoint, to: &mut ChannelEndpoint, time: f64) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for datagram in from.flush(time).unwrap() {
+            messages.extend(to.receive(&datagram).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn reliable_messages_arrive_in_order_despite_loss() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+
+        a.send(Channel::Reliable, vec![0]);
+        // the first flush is lost
+        assert!(!a.flush(0.0).unwrap().is_empty());
+        a.send(Channel::Reliable, vec![1]);
+        let first = deliver(&mut a, &mut b, 0.01);
+        // message 1 is held back until message 0 has arrived
+        assert!(first.is_empty());
+
+        let resent = deliver(&mut a, &mut b, RESEND_INTERVAL + 0.01);
+        assert_eq!(resent, vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn reliable_messages_are_delivered_once() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+
+        a.send(Channel::Reliable, vec![7]);
+        let datagrams = a.flush(0.0).unwrap();
+        assert_eq!(b.receive(&datagrams[0]).unwrap(), vec![vec![7]]);
+        assert!(b.receive(&datagrams[0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn acked_messages_are_not_resent() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+
+        a.send(Channel::Reliable, vec![1]);
+        deliver(&mut a, &mut b, 0.0);
+        deliver(&mut b, &mut a, 0.0);
+        assert!(a.flush(RESEND_INTERVAL * 2.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreliable_messages_are_sent_once() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+
+        a.send(Channel::Unreliable, vec![3]);
+        assert_eq!(deliver(&mut a, &mut b, 0.0), vec![vec![3]]);
+        assert!(a.flush(RESEND_INTERVAL * 2.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flushes_are_split_into_datagrams_below_the_size_limit() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+
+        for i in 0..20 {
+            a.send(Channel::Reliable, vec![i; 300]);
+            a.send(Channel::Unreliable, vec![i; 100]);
+        }
+        let datagrams = a.flush(0.0).unwrap();
+        assert!(datagrams.len() > 1);
+        for datagram in datagrams.iter() {
+            assert!(datagram.len() <= MAX_DATAGRAM_SIZE);
+        }
+
+        let mut messages = Vec::new();
+        for datagram in datagrams {
+            messages.extend(b.receive(&datagram).unwrap());
+        }
+        assert_eq!(messages.len(), 40);
+    }
+
+    #[test]
+    fn oversized_messages_get_a_datagram_of_their_own() {
+        let mut a = ChannelEndpoint::new();
+        a.send(Channel::Reliable, vec![0; MAX_DATAGRAM_SIZE * 2]);
+        a.send(Channel::Unreliable, vec![1]);
+
+        let datagrams = a.flush(0.0).unwrap();
+        assert_eq!(datagrams.len(), 2);
+    }
+
+    #[test]
+    fn size_estimates_match_the_encoding() {
+        let datagram = Datagram {
+            acks: vec![1],
+            reliable: vec![(2, vec![0; 10])],
+            unreliable: vec![vec![0; 20]],
+        };
+        let size = DATAGRAM_HEADER_SIZE
+            + ACK_SIZE
+            + RELIABLE_HEADER_SIZE
+            + 10
+            + UNRELIABLE_HEADER_SIZE
+            + 20;
+        assert_eq!(bincode::serialize(&datagram).unwrap().len(), size);
+    }
+
+    #[test]
+    fn peer_is_lost_after_too_many_unacked_sends() {
+        let mut a = ChannelEndpoint::new();
+        a.send(Channel::Reliable, vec![1]);
+
+        for i in 0..MAX_SENDS {
+            a.flush(i as f64 * RESEND_INTERVAL * 2.0).unwrap();
+            assert!(!a.is_lost());
+        }
+        a.flush(MAX_SENDS as f64 * RESEND_INTERVAL * 2.0).unwrap();
+        assert!(a.is_lost());
+    }
+
+    #[test]
+    fn peer_is_lost_when_too_much_is_left_unacked() {
+        let mut a = ChannelEndpoint::new();
+        let mut b = ChannelEndpoint::new();
+        let message = vec![0; MAX_UNACKED_BYTES / 4];
+
+        // acked data doesn't count towards the limit
+        for _ in 0..4 {
+            a.send(Channel::Reliable, message.clone());
+        }
+        deliver(&mut a, &mut b, 0.0);
+        deliver(&mut b, &mut a, 0.0);
+        a.send(Channel::Reliable, message.clone());
+        assert!(!a.is_lost());
+
+        for _ in 0..4 {
+            a.send(Channel::Reliable, message.clone());
+        }
+        assert!(a.is_lost());
+    }
+
+    #[test]
+    fn malformed_datagrams_are_protocol_errors() {
+        let mut a = ChannelEndpoint::new();
+        let error = a.receive(&[1, 2, 3]).unwrap_err();
+        assert!(error.downcast_ref::<ProtocolError>().is_some());
+    }
+}
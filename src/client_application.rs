@@ -0,0 +1,39 @@
+use failure::Error;
+
+use embla::input::Input;
+use embla::window::Window;
+
+use game_client::{ClientSettings, GameClient};
+use renderer::GameRenderer;
+use transport::Transport;
+
+/// Runs only the client, playing on a server reached through the given transport.
+pub struct ClientApplication {
+    renderer: GameRenderer,
+    client: GameClient,
+    window: Window,
+}
+
+impl ClientApplication {
+    pub fn new(
+        window: Window,
+        transport: Box<Transport>,
+        settings: ClientSettings,
+    ) -> Result<Self, Error> {
+        Ok(ClientApplication {
+            renderer: GameRenderer::new(&window.renderer())?,
+            client: GameClient::new(transport, settings)?,
+            window,
+        })
+    }
+
+    pub fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error> {
+        self.client.update(dt, input)?;
+
+        self.client.render(&mut self.renderer)?;
+
+        self.renderer.do_render(&self.window.renderer()).unwrap();
+
+        Ok(())
+    }
+}
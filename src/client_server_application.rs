@@ -3,28 +3,37 @@ use failure::Error;
 use embla::input::Input;
 use embla::window::Window;
 
-use game_client::GameClient;
+use game_client::{ClientSettings, GameClient};
 use game_server::GameServer;
 use renderer::GameRenderer;
+use transport::{MemoryServerTransport, SimulatedTransport, SimulatorConfig, Transport};
 
 pub struct ClientServerApplication {
     renderer: GameRenderer,
-    tick: usize,
     server: GameServer,
     client: GameClient,
     window: Window,
 }
 
 impl ClientServerApplication {
-    pub fn new(window: Window) -> Result<Self, Error> {
-        let mut server = GameServer::new()?;
-        let client = GameClient::new()?;
-
-        server.add_client(0);
+    /// The optional simulator config applies network conditions to the link between the server
+    /// and the client.
+    pub fn new(
+        window: Window,
+        simulator: Option<SimulatorConfig>,
+        settings: ClientSettings,
+    ) -> Result<Self, Error> {
+        let server_transport = MemoryServerTransport::new();
+        let client_transport: Box<Transport> = match simulator {
+            Some(config) => Box::new(SimulatedTransport::new(server_transport.connect()?, config)),
+            None => Box::new(server_transport.connect()?),
+        };
+
+        let server = GameServer::new(Box::new(server_transport))?;
+        let client = GameClient::new(client_transport, settings)?;
 
         Ok(ClientServerApplication {
             renderer: GameRenderer::new(&window.renderer())?,
-            tick: 0,
             server,
             client,
             window,
@@ -34,24 +43,12 @@ impl ClientServerApplication {
     pub fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error> {
         self.server.update(dt)?;
 
-        let packets = self.server.take_outgoing(&0).unwrap();
-        for packet in packets {
-            self.client.handle_incoming(packet)?;
-        }
-
         self.client.update(dt, input)?;
 
-        let packets = self.client.take_outgoing();
-        for packet in packets {
-            self.server.handle_incoming(0, &packet)?;
-        }
-
         self.client.render(&mut self.renderer)?;
 
         self.renderer.do_render(&self.window.renderer()).unwrap();
 
-        self.tick += 1;
-
         Ok(())
     }
 }
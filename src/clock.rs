@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+use game_server::TIMESTEP;
+use packets::Tick;
+
+// seconds between pings sent to the server
+static PING_INTERVAL: f64 = 0.5;
+// number of recent round trips the offset estimate is picked from
+static SAMPLE_COUNT: usize = 16;
+// weight of a new round trip measurement in the smoothed round trip time
+static RTT_SMOOTHING: f64 = 0.1;
+// the applied offset moves towards the estimate by at most this fraction of the elapsed time,
+// so the server clock as seen by the client never jumps or runs backwards
+static MAX_SLEW: f64 = 0.05;
+// offset errors larger than this in seconds are corrected at once instead
+static MAX_SLEWED_ERROR: f64 = 0.25;
+
+/// Client side estimate of the server's clock, from the ticks in server updates and ping round
+/// trips. Times are in seconds, the server time of a tick is `tick * TIMESTEP`.
+pub struct Clock {
+    last_ping: Option<f64>,
+    rtt: Option<f64>,
+    // (round trip time, server time minus local time) of the most recent pongs
+    samples: VecDeque<(f64, f64)>,
+    target_offset: Option<f64>,
+    offset: Option<f64>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            last_ping: None,
+            rtt: None,
+            samples: VecDeque::with_capacity(SAMPLE_COUNT),
+            target_offset: None,
+            offset: None,
+        }
+    }
+
+    /// Whether it's time to send another ping, given the local time.
+    pub fn ping_due(&mut self, time: f64) -> bool {
+        if self.last_ping.map(|t| time - t < PING_INTERVAL).unwrap_or(false) {
+            return false;
+        }
+        self.last_ping = Some(time);
+        true
+    }
+
+    /// Records a pong received at the given local time, for a ping sent at `ping_time`.
+    pub fn pong(&mut self, time: f64, ping_time: f64, tick: Tick) {
+        let rtt = (time - ping_time).max(0.0);
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => smoothed + (rtt - smoothed) * RTT_SMOOTHING,
+            None => rtt,
+        });
+
+        // the server replied half a round trip ago, assuming the route is symmetric
+        let offset = tick as f64 * TIMESTEP + rtt / 2.0 - time;
+        if self.samples.len() >= SAMPLE_COUNT {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, offset));
+
+        // queueing only ever delays packets, so the fastest round trip gives the best estimate
+        self.target_offset = self
+            .samples
+            .iter()
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+            .map(|&(_, offset)| offset);
+        if self.offset.is_none() {
+            self.offset = self.target_offset;
+        }
+    }
+
+    /// Records a server update taken at the given tick. Only used as a rough estimate until the
+    /// first pong arrives.
+    pub fn update_received(&mut self, time: f64, tick: Tick) {
+        if self.offset.is_none() {
+            self.offset = Some(tick as f64 * TIMESTEP - time);
+        }
+    }
+
+    /// Moves the applied offset towards the current estimate, called once per client update.
+    pub fn advance(&mut self, dt: f64) {
+        if let (Some(offset), Some(target)) = (self.offset, self.target_offset) {
+            let error = target - offset;
+            let max_step = dt * MAX_SLEW;
+            self.offset = Some(if error.abs() > MAX_SLEWED_ERROR {
+                target
+            } else {
+                offset + error.max(-max_step).min(max_step)
+            });
+        }
+    }
+
+    /// Smoothed round trip time to the server.
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// The server's time at the given local time.
+    pub fn server_time(&self, time: f64) -> Option<f64> {
+        self.offset.map(|offset| time + offset)
+    }
+
+    /// The server tick at the given local time, with the fraction of the tick in progress.
+    pub fn server_tick(&self, time: f64) -> Option<f64> {
+        self.server_time(time).map(|server_time| server_time / TIMESTEP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn pings_at_the_interval() {
+        let mut clock = Clock::new();
+        assert!(clock.ping_due(0.0));
+        assert!(!clock.ping_due(PING_INTERVAL / 2.0));
+        assert!(clock.ping_due(PING_INTERVAL));
+    }
+
+    #[test]
+    fn estimates_from_updates_until_the_first_pong() {
+        let mut clock = Clock::new();
+        assert!(clock.server_time(1.0).is_none());
+
+        clock.update_received(1.0, 100);
+        assert_close(clock.server_time(1.0).unwrap(), 100.0 * TIMESTEP);
+        assert_close(clock.server_time(2.0).unwrap(), 100.0 * TIMESTEP + 1.0);
+    }
+
+    #[test]
+    fn offset_comes_from_the_fastest_round_trip() {
+        let mut clock = Clock::new();
+        // a slow round trip whose reply was queued for a while
+        clock.pong(10.3, 10.0, 1000);
+        // a fast one, the server replied at tick 1060 halfway through
+        clock.pong(11.05, 11.0, 1060);
+
+        let expected = 1060.0 * TIMESTEP + 0.025 - 11.05;
+        clock.advance(1000.0);
+        assert_close(clock.server_time(0.0).unwrap(), expected);
+
+        // slower round trips after it don't move the estimate
+        clock.pong(12.4, 12.0, 1080);
+        clock.advance(1000.0);
+        assert_close(clock.server_time(0.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn small_errors_are_slewed_and_large_ones_snapped() {
+        let mut clock = Clock::new();
+        clock.update_received(0.0, 0);
+        clock.pong(1.0, 1.0, 0);
+        // the update gave an offset of 0, the pong says -1 which is too far off to slew
+        clock.advance(0.01);
+        assert_close(clock.server_time(1.0).unwrap(), 0.0);
+
+        let mut clock = Clock::new();
+        clock.update_received(0.0, 0);
+        clock.pong(0.1, 0.1, 0);
+        clock.advance(1.0);
+        assert_close(clock.server_time(0.0).unwrap(), -MAX_SLEW);
+        clock.advance(1.0);
+        assert_close(clock.server_time(0.0).unwrap(), -0.1);
+    }
+
+    #[test]
+    fn smooths_the_round_trip_time() {
+        let mut clock = Clock::new();
+        assert!(clock.rtt().is_none());
+        clock.pong(0.1, 0.0, 0);
+        assert_close(clock.rtt().unwrap(), 0.1);
+        clock.pong(1.2, 1.0, 0);
+        assert_close(clock.rtt().unwrap(), 0.1 + 0.1 * RTT_SMOOTHING);
+    }
+}
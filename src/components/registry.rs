@@ -15,5 +15,5 @@ pub fn register_components(world: &mut World, net_adapter: &mut NetComponentAdap
     world.register::<Velocity>();
     world.register::<Networked>();
 
-    net_adapter.register_component::<Transform>();
+    net_adapter.register_component::<Transform>("transform");
 }
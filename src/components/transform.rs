@@ -1,13 +1,23 @@
-use bincode;
+use std::f32::consts::PI;
+
 use embla::math::Vec2;
 use specs::{Component, VecStorage};
 
-use net::NetComponent;
+/// Entities are kept within this distance of the origin on both axes, positions are sent to
+/// clients in that range.
+pub static WORLD_BOUND: f32 = 4096.0;
+// positions are sent to 1/16 px
+static POSITION_BITS: u32 = 17;
+static ROTATION_BITS: u32 = 10;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, NetComponent)]
 pub struct Transform {
+    #[net(quantize = "-WORLD_BOUND, WORLD_BOUND, POSITION_BITS")]
     pub position: Vec2<f32>,
+    // almost never changed, a single bit unless it is
+    #[net(default = 1.0)]
     pub scale: f32,
+    #[net(quantize = "angle, ROTATION_BITS")]
     pub rotation: f32,
 }
 
@@ -21,22 +31,174 @@ impl Default for Transform {
     }
 }
 
+impl Transform {
+    /// Blends towards another transform by `t` in `[0, 1]`, rotating the shorter way around.
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        let rotation_delta = self.rotation_to(other);
+        Transform {
+            position: Vec2::new(
+                self.position.x + (other.position.x - self.position.x) * t,
+                self.position.y + (other.position.y - self.position.y) * t,
+            ),
+            scale: self.scale + (other.scale - self.scale) * t,
+            rotation: self.rotation + rotation_delta * t,
+        }
+    }
+
+    /// The rotation from this transform's to the other's, the shorter way around.
+    pub fn rotation_to(&self, other: &Transform) -> f32 {
+        let delta = (other.rotation - self.rotation) % (2.0 * PI);
+        if delta > PI {
+            delta - 2.0 * PI
+        } else if delta < -PI {
+            delta + 2.0 * PI
+        } else {
+            delta
+        }
+    }
+}
+
 impl Component for Transform {
     type Storage = VecStorage<Self>;
 }
 
-impl NetComponent for Transform {
-    fn net_store(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("error serializing Transform")
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bits::BitReader;
+    use net::NetComponent;
+
+    fn transform(x: f32, y: f32, rotation: f32) -> Transform {
+        Transform {
+            position: Vec2::new(x, y),
+            scale: 1.0,
+            rotation,
+        }
+    }
+
+    fn assert_close(a: &Transform, b: &Transform) {
+        assert!((a.position.x - b.position.x).abs() < 0.1);
+        assert!((a.position.y - b.position.y).abs() < 0.1);
+        assert_eq!(a.scale, b.scale);
+        assert!(a.rotation_to(b).abs() < 0.01);
+    }
+
+    #[test]
+    fn rotation_takes_the_short_way_across_zero() {
+        let a = transform(0.0, 0.0, 0.1);
+        let b = transform(0.0, 0.0, 2.0 * PI - 0.1);
+        assert!((a.rotation_to(&b) + 0.2).abs() < 0.001);
+        assert!((b.rotation_to(&a) - 0.2).abs() < 0.001);
+
+        assert!(a.lerp(&b, 0.5).rotation.abs() < 0.001);
+        assert!((a.lerp(&b, 0.25).rotation - 0.05).abs() < 0.001);
+    }
+
+    #[test]
+    fn lerp_blends_position_and_scale() {
+        let a = transform(0.0, 10.0, 0.0);
+        let mut b = transform(10.0, 30.0, 0.0);
+        b.scale = 3.0;
+
+        let blended = a.lerp(&b, 0.25);
+        assert_eq!(blended.position, Vec2::new(2.5, 15.0));
+        assert_eq!(blended.scale, 1.5);
+    }
+
+    #[test]
+    fn store_load_round_trip() {
+        let original = transform(123.4, -567.8, 1.5);
+        let mut loaded = Transform::default();
+        loaded.net_load(&original.net_store().unwrap()).unwrap();
+        assert_close(&original, &loaded);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let baseline = transform(10.0, 20.0, 0.5);
+        let current = transform(15.0, 20.0, 2.0);
+        let baseline_data = baseline.net_store().unwrap();
+
+        let delta = current.read_delta(Some(&baseline_data)).unwrap();
+        let mut loaded = baseline.clone();
+        loaded.write_delta(delta.as_bytes()).unwrap();
+        assert_close(&current, &loaded);
+
+        let full = current.read_delta(None).unwrap();
+        let mut loaded = Transform::default();
+        loaded.write_delta(full.as_bytes()).unwrap();
+        assert_close(&current, &loaded);
+    }
+
+    #[test]
+    fn delta_only_includes_changed_fields() {
+        let baseline = transform(10.0, 20.0, 0.5);
+        let mut current = baseline.clone();
+        current.rotation = 1.0;
+
+        let delta = current.read_delta(Some(&baseline.net_store().unwrap())).unwrap();
+        // a changed flag per field and the rotation
+        assert_eq!(delta.len(), 3 + ROTATION_BITS as usize);
+        let mut loaded = baseline.clone();
+        loaded.write_delta(delta.as_bytes()).unwrap();
+        assert_close(&current, &loaded);
+
+        let unchanged = baseline.read_delta(Some(&baseline.net_store().unwrap())).unwrap();
+        assert_eq!(unchanged.len(), 3);
+    }
+
+    #[test]
+    fn scale_is_only_sent_when_not_the_default() {
+        let mut scaled = transform(1.0, 2.0, 3.0);
+        scaled.scale = 2.5;
+        let default_size = transform(1.0, 2.0, 3.0).read_delta(None).unwrap().len();
+        let scaled_delta = scaled.read_delta(None).unwrap();
+        assert_eq!(scaled_delta.len(), default_size + 32);
+
+        let mut loaded = Transform::default();
+        loaded.write_delta(scaled_delta.as_bytes()).unwrap();
+        assert_eq!(loaded.scale, 2.5);
+        loaded.net_load(&transform(1.0, 2.0, 3.0).net_store().unwrap()).unwrap();
+        assert_eq!(loaded.scale, 1.0);
+    }
+
+    #[test]
+    fn positions_at_the_world_bounds_round_trip() {
+        for &(x, y) in [(WORLD_BOUND, -WORLD_BOUND), (-WORLD_BOUND, WORLD_BOUND)].iter() {
+            let original = transform(x, y, 0.0);
+            let mut loaded = Transform::default();
+            loaded.net_load(&original.net_store().unwrap()).unwrap();
+            assert_eq!(loaded.position, original.position);
+        }
     }
-    fn net_load(&mut self, data: &[u8]) {
-        *self = bincode::deserialize(data).expect("error deserializing Transform");
+
+    #[test]
+    fn schema_describes_the_values_of_constants() {
+        let schema = Transform::net_schema();
+        assert!(schema.contains("quantize(-4096.0, 4096.0, 17)"), "{}", schema);
+        assert!(schema.contains("angle(10)"), "{}", schema);
+        assert!(schema.contains("default(1.0)"), "{}", schema);
     }
 
-    fn read_delta(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("error serializing Transform")
+    #[test]
+    fn skipping_a_delta_stops_where_it_ends() {
+        let mut scaled = transform(1.0, 2.0, 3.0);
+        scaled.scale = 2.5;
+        let mut writer = scaled.read_delta(None).unwrap();
+        let first_len = writer.len();
+        writer.append(&transform(4.0, 5.0, 6.0).read_delta(None).unwrap());
+
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        Transform::skip_delta(&mut reader).unwrap();
+        assert_eq!(reader.position(), first_len);
+        Transform::skip_delta(&mut reader).unwrap();
     }
-    fn write_delta(&mut self, data: &[u8]) {
-        *self = bincode::deserialize(data).expect("error deserializing Transform");
+
+    #[test]
+    fn truncated_data_is_an_error() {
+        let data = transform(1.0, 2.0, 3.0).net_store().unwrap();
+        let mut loaded = Transform::default();
+        assert!(loaded.net_load(&data[..data.len() - 1]).is_err());
     }
 }
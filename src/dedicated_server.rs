@@ -0,0 +1,29 @@
+use failure::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use game_server::{GameServer, TIMESTEP};
+use transport::Transport;
+
+/// Runs a headless server accepting clients through the given transport until it errors.
+pub fn run(transport: Box<Transport>) -> Result<(), Error> {
+    let mut server = GameServer::new(transport)?;
+
+    let timestep = Duration::from_millis((TIMESTEP * 1000.0) as u64);
+    let mut last_update = Instant::now();
+    loop {
+        let elapsed = last_update.elapsed();
+        last_update = Instant::now();
+        let dt = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        server.update(dt)?;
+        for &(client_id, ref reason) in server.dropped_clients() {
+            eprintln!("dropped client {}: {}", client_id, reason);
+        }
+
+        let update_time = last_update.elapsed();
+        if update_time < timestep {
+            thread::sleep(timestep - update_time);
+        }
+    }
+}
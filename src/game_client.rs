@@ -1,15 +1,62 @@
 use failure::Error;
+use std::collections::{HashMap, VecDeque};
 use std::mem;
 
 use embla::input::{Input, Key};
-use specs::{Join, World};
+use embla::math::Vec2;
+use specs::{Entity, Join, RunNow, World};
 
+use channel::ChannelEndpoint;
 use components;
-use components::{Networked, Sprite, Transform};
-use net::NetComponentAdapter;
-use packets::{EntitiesStore, Packet};
+use clock::Clock;
+use components::{Networked, Player, Sprite, Transform, Velocity};
+use game_server::{MAX_STEPS_PER_UPDATE, TIMESTEP};
+use interpolation::Interpolation;
+use net::{ClientId, EntityId, NetComponentAdapter, ProtocolError, SnapshotBuffer};
+use packets;
+use packets::{EntitiesStore, InputSequence, Packet, Tick, PROTOCOL_VERSION};
 use prefab;
 use render_interface::RenderInterface;
+use systems::{MovementSystem, PlayerControlSystem};
+use transport::{Transport, TransportEvent};
+
+// inputs the server hasn't processed yet are kept for at most this many ticks
+static MAX_PENDING_INPUTS: usize = 120;
+// fraction of a misprediction that is still visible after each step
+static PREDICTION_ERROR_DECAY: f32 = 0.85;
+// mispredictions larger than this are snapped to instead of smoothed over
+static MAX_SMOOTHED_ERROR: f32 = 100.0;
+
+pub struct ClientSettings {
+    /// How far in the past other entities are drawn, in seconds.
+    pub interpolation_delay: f64,
+    pub player_name: String,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            interpolation_delay: 0.1,
+            player_name: "player".to_string(),
+        }
+    }
+}
+
+/// State of the keys controlling our ship.
+#[derive(Clone, Copy)]
+struct Controls {
+    left: bool,
+    right: bool,
+    up: bool,
+}
+
+#[derive(Clone, Copy)]
+struct PendingInput {
+    sequence: InputSequence,
+    left: bool,
+    right: bool,
+    up: bool,
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum GameState {
@@ -19,16 +66,42 @@ enum GameState {
 }
 
 pub struct GameClient {
+    transport: Box<Transport>,
+    server: Option<ClientId>,
+    channel: ChannelEndpoint,
+    time: f64,
+    // elapsed time not yet simulated
+    accumulator: f64,
     world: World,
     prefabs: prefab::Registry,
     outgoing: Vec<Packet>,
     state: GameState,
+    player_name: String,
 
     net_adapter: NetComponentAdapter,
+    net_entities: HashMap<EntityId, Entity>,
+    // the id of the entity currently using each index, to find stale ones when it's reused
+    net_entity_ids: HashMap<u16, EntityId>,
+    snapshots: SnapshotBuffer,
+    clock: Clock,
+    interpolation: Interpolation,
+
+    // the ship controlled by this client, simulated locally ahead of the server
+    player_entity: Option<EntityId>,
+    input_sequence: InputSequence,
+    pending_inputs: VecDeque<PendingInput>,
+    // how far the drawn ship is from the predicted one after a correction
+    position_error: Vec2<f32>,
+    rotation_error: f32,
+    // where entities are drawn as of the last two steps, frames in between blend the two
+    previous_transforms: HashMap<Entity, Transform>,
+    current_transforms: HashMap<Entity, Transform>,
+    movement_system: MovementSystem,
+    player_control_system: PlayerControlSystem,
 }
 
 impl GameClient {
-    pub fn new() -> Result<GameClient, Error> {
+    pub fn new(transport: Box<Transport>, settings: ClientSettings) -> Result<GameClient, Error> {
         let mut net_adapter = NetComponentAdapter::new();
         let mut world = World::new();
         components::register_components(&mut world, &mut net_adapter);
@@ -37,75 +110,427 @@ impl GameClient {
         prefab::register_prefabs(&mut prefabs);
 
         Ok(GameClient {
+            transport,
+            server: None,
+            channel: ChannelEndpoint::new(),
+            time: 0.0,
+            accumulator: 0.0,
             world,
             prefabs,
             outgoing: Vec::new(),
             state: GameState::Start,
+            player_name: settings.player_name,
 
             net_adapter,
+            net_entities: HashMap::new(),
+            net_entity_ids: HashMap::new(),
+            snapshots: SnapshotBuffer::new(),
+            clock: Clock::new(),
+            interpolation: Interpolation::new(settings.interpolation_delay),
+
+            player_entity: None,
+            input_sequence: 0,
+            pending_inputs: VecDeque::new(),
+            position_error: Vec2::zero(),
+            rotation_error: 0.0,
+            previous_transforms: HashMap::new(),
+            current_transforms: HashMap::new(),
+            movement_system: MovementSystem::new(),
+            player_control_system: PlayerControlSystem::new(),
         })
     }
 
-    pub fn handle_incoming(&mut self, packet: Packet) -> Result<(), Error> {
+    fn handle_incoming(&mut self, packet: Packet) -> Result<(), Error> {
         match packet {
-            Packet::Initialize => {
+            Packet::Initialize {
+                player_entity,
+                manifest,
+            } => {
                 if self.state == GameState::Connecting {
+                    // everything the server sends refers to components and prefabs by its own
+                    // indices, which needn't match ours
+                    self.net_adapter.set_remote_manifest(&manifest.components)?;
+                    self.prefabs.set_remote_manifest(&manifest.prefabs)?;
                     self.state = GameState::Running;
+                    self.player_entity = Some(player_entity);
                 } else {
-                    return Err(format_err!("unexpected initialize packet"));
+                    return Err(ProtocolError::UnexpectedPacket("initialized twice").into());
                 }
             }
+            Packet::ConnectRejected { reason } => {
+                return Err(format_err!("server rejected the connection: {}", reason));
+            }
             Packet::CreateEntities(EntitiesStore {
                 entities,
                 components,
             }) => {
                 for (entity_id, prefab) in entities {
+                    // an entity reusing the index of one we still have means the old one was
+                    // destroyed without us hearing about it
+                    if let Some(stale_id) = self.net_entity_ids.get(&entity_id.index).cloned() {
+                        self.destroy_net_entity(stale_id)?;
+                    }
+
+                    let prefab = self.prefabs.local_index(prefab)?;
                     let e = self.prefabs.instantiate(&mut self.world, prefab)?;
                     self.world
                         .write_storage::<Networked>()
                         .insert(e, Networked { entity_id, prefab })?;
+                    self.net_entities.insert(entity_id, e);
+                    self.net_entity_ids.insert(entity_id.index, entity_id);
+
+                    // only our own ship is controlled and moved locally, the initialize packet
+                    // is reliably delivered before any entities so we know which one it is
+                    if Some(entity_id) != self.player_entity {
+                        self.world.write_storage::<Player>().remove(e);
+                        self.world.write_storage::<Velocity>().remove(e);
+                    }
+                }
+
+                let unknown = self
+                    .net_adapter
+                    .net_load(&self.world, &self.net_entities, components)?;
+                if !unknown.is_empty() {
+                    let unknown = unknown.into_iter().collect();
+                    return Err(ProtocolError::UnknownEntities(unknown).into());
+                }
+            }
+            Packet::DestroyEntities(entity_ids) => {
+                for entity_id in entity_ids {
+                    self.destroy_net_entity(entity_id)?;
+                }
+            }
+            // updates and pongs travel unreliably and can overtake the reliable initialize
+            // packet, before which the server's component indices aren't known
+            Packet::Update { .. } | Packet::Pong { .. } if self.state != GameState::Running => {}
+            Packet::Update {
+                sequence,
+                tick,
+                baseline,
+                last_input,
+                delta,
+            } => {
+                if self.snapshots.latest().map(|s| sequence <= s).unwrap_or(false) {
+                    // a newer snapshot has already been applied
+                    return Ok(());
                 }
 
-                self.net_adapter.net_load(&self.world, components);
+                self.clock.update_received(self.time, tick);
+                let predicted = self.player_transform();
+
+                let (components, unknown) = {
+                    let baseline = match baseline {
+                        Some(baseline) => match self.snapshots.get(baseline) {
+                            Some(baseline) => Some(baseline),
+                            // the baseline is too old to still be buffered, drop the update and
+                            // let the server move on to a newer baseline
+                            None => return Ok(()),
+                        },
+                        None => None,
+                    };
+                    let delta = self.net_adapter.decode_delta(&delta)?;
+                    self.net_adapter
+                        .write_delta(&self.world, &self.net_entities, baseline, delta)?
+                };
+
+                // updates travel unreliably and can overtake the reliable packet creating an
+                // entity. Such an update isn't acknowledged, so the server keeps including the
+                // entities we missed until we've caught up.
+                if unknown.is_empty() {
+                    self.snapshots.push(sequence, components);
+                    self.outgoing.push(Packet::SnapshotAck(sequence));
+                }
+
+                let transforms = self.remote_transforms();
+                self.interpolation.push(tick as f64 * TIMESTEP, transforms);
+
+                self.reconcile(last_input, predicted);
             }
-            Packet::Update(component_delta) => {
-                self.net_adapter.write_delta(&self.world, component_delta);
+            Packet::Pong { time, tick } => {
+                self.clock.pong(self.time, time, tick);
             }
             _ => {
-                return Err(format_err!("client received unexpected packet"));
+                return Err(ProtocolError::UnexpectedPacket("only sent by clients").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the local entity mapped from a net entity id, ids that are already gone are
+    /// ignored.
+    fn destroy_net_entity(&mut self, entity_id: EntityId) -> Result<(), Error> {
+        if let Some(e) = self.net_entities.remove(&entity_id) {
+            self.net_entity_ids.remove(&entity_id.index);
+            self.world.delete_entity(e)?;
+        }
+
+        Ok(())
+    }
+
+    /// Smoothed round trip time to the server in seconds, once it has been measured.
+    pub fn rtt(&self) -> Option<f64> {
+        self.clock.rtt()
+    }
+
+    /// Estimate of the server tick currently being simulated, including the fraction of it that
+    /// has passed.
+    pub fn server_tick(&self) -> Option<f64> {
+        self.clock.server_tick(self.time)
+    }
+
+    fn player_ship(&self) -> Option<Entity> {
+        self.player_entity
+            .and_then(|entity_id| self.net_entities.get(&entity_id).cloned())
+    }
+
+    fn player_transform(&self) -> Option<Transform> {
+        self.player_ship()
+            .and_then(|e| self.world.read_storage::<Transform>().get(e).cloned())
+    }
+
+    /// Transforms of the networked entities that are drawn interpolated, all but our own ship.
+    fn remote_transforms(&self) -> HashMap<EntityId, Transform> {
+        let transform = self.world.read_storage::<Transform>();
+        self.net_entities
+            .iter()
+            .filter(|&(entity_id, _)| Some(*entity_id) != self.player_entity)
+            .filter_map(|(entity_id, &e)| transform.get(e).map(|t| (*entity_id, t.clone())))
+            .collect()
+    }
+
+    /// Applies an input to our own ship and advances it one tick, without waiting for the
+    /// server.
+    fn predict(&mut self, input: PendingInput) {
+        if let Some(e) = self.player_ship() {
+            if let Some(player) = self.world.write_storage::<Player>().get_mut(e) {
+                player.left = input.left;
+                player.right = input.right;
+                player.up = input.up;
+            }
+        }
+
+        self.player_control_system.run_now(&self.world.res);
+        self.movement_system.run_now(&self.world.res);
+    }
+
+    /// Our ship has just been set to the server's state as of the last input it processed.
+    /// Replays the inputs the server hasn't seen yet on top of it, and carries over any
+    /// difference from what we had predicted as a visual error that fades out.
+    fn reconcile(&mut self, last_input: Option<InputSequence>, predicted: Option<Transform>) {
+        if let Some(last_input) = last_input {
+            while self
+                .pending_inputs
+                .front()
+                .map(|input| input.sequence <= last_input)
+                .unwrap_or(false)
+            {
+                self.pending_inputs.pop_front();
+            }
+        }
+
+        let replay: Vec<PendingInput> = self.pending_inputs.iter().cloned().collect();
+        for input in replay {
+            self.predict(input);
+        }
+
+        if let (Some(predicted), Some(corrected)) = (predicted, self.player_transform()) {
+            let error = Vec2::new(
+                self.position_error.x + predicted.position.x - corrected.position.x,
+                self.position_error.y + predicted.position.y - corrected.position.y,
+            );
+            if error.x.abs() + error.y.abs() > MAX_SMOOTHED_ERROR {
+                self.position_error = Vec2::zero();
+                self.rotation_error = 0.0;
+            } else {
+                self.position_error = error;
+                self.rotation_error += corrected.rotation_to(&predicted);
+            }
+        }
+    }
+
+    fn poll_transport(&mut self) -> Result<(), Error> {
+        for event in self.transport.poll()? {
+            match event {
+                TransportEvent::Connected(server) => self.server = Some(server),
+                TransportEvent::Disconnected(_) => {
+                    self.server = None;
+                    return Err(format_err!("disconnected from server"));
+                }
+                TransportEvent::Received(_, data) => {
+                    for message in self.channel.receive(&data)? {
+                        let packet = Packet::decode(&message)?;
+                        self.handle_incoming(packet)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush_outgoing(&mut self) -> Result<(), Error> {
+        // packets are held back until the transport has connected
+        if let Some(server) = self.server {
+            for packet in mem::replace(&mut self.outgoing, Vec::new()) {
+                self.channel.send(packet.channel(), packet.encode()?);
+            }
+            for datagram in self.channel.flush(self.time)? {
+                self.transport.send(server, datagram)?;
+            }
+            if self.channel.is_lost() {
+                return Err(format_err!("connection to server lost"));
             }
         }
 
         Ok(())
     }
 
-    pub fn take_outgoing(&mut self) -> Vec<Packet> {
-        mem::replace(&mut self.outgoing, Vec::new())
+    pub fn update(&mut self, dt: f64, input: &Input) -> Result<(), Error> {
+        let controls = Controls {
+            left: input.key_is_down(&Key::A),
+            right: input.key_is_down(&Key::D),
+            up: input.key_is_down(&Key::W),
+        };
+        self.advance(dt, controls)
     }
 
-    pub fn update(&mut self, _: f64, input: &Input) -> Result<(), Error> {
+    fn advance(&mut self, dt: f64, controls: Controls) -> Result<(), Error> {
+        self.time += dt;
+        self.transport.tick(dt)?;
+        self.poll_transport()?;
+
+        self.clock.advance(dt);
+
         match self.state {
             GameState::Start => {
-                self.outgoing.push(Packet::Connect);
-                self.state = GameState::Connecting;
+                if self.server.is_some() {
+                    self.outgoing.push(Packet::Connect {
+                        protocol_version: PROTOCOL_VERSION,
+                        checksum: packets::registry_checksum(&self.net_adapter, &self.prefabs),
+                        player_name: self.player_name.clone(),
+                    });
+                    self.state = GameState::Connecting;
+                }
             }
             GameState::Connecting => {}
             GameState::Running => {
-                self.outgoing.push(Packet::PlayerInput {
-                    left: input.key_is_down(&Key::A),
-                    right: input.key_is_down(&Key::D),
-                    up: input.key_is_down(&Key::W),
-                });
+                if self.clock.ping_due(self.time) {
+                    self.outgoing.push(Packet::Ping { time: self.time });
+                }
+
+                // the local ship is predicted in the same fixed steps the server simulates in,
+                // each step sends one input for the server to apply in one of its steps
+                self.accumulator += dt;
+                let mut steps = 0;
+                while self.accumulator >= TIMESTEP {
+                    if steps == MAX_STEPS_PER_UPDATE {
+                        self.accumulator = 0.0;
+                        break;
+                    }
+                    self.accumulator -= TIMESTEP;
+                    self.step(controls);
+                    steps += 1;
+                }
             }
         }
 
+        self.flush_outgoing()?;
+
         Ok(())
     }
 
+    /// Predicts our own ship one `TIMESTEP` ahead with the given controls, then records where
+    /// every entity is to be drawn as of this step.
+    fn step(&mut self, controls: Controls) {
+        let input = PendingInput {
+            sequence: self.input_sequence,
+            left: controls.left,
+            right: controls.right,
+            up: controls.up,
+        };
+        self.input_sequence += 1;
+
+        self.predict(input);
+        self.pending_inputs.push_back(input);
+        while self.pending_inputs.len() > MAX_PENDING_INPUTS {
+            self.pending_inputs.pop_front();
+        }
+
+        self.position_error = Vec2::new(
+            self.position_error.x * PREDICTION_ERROR_DECAY,
+            self.position_error.y * PREDICTION_ERROR_DECAY,
+        );
+        self.rotation_error *= PREDICTION_ERROR_DECAY;
+
+        let step_time = self.time - self.accumulator;
+        if let Some(server_time) = self.clock.server_time(step_time) {
+            self.interpolation.advance(server_time);
+        }
+        self.capture_transforms();
+
+        let view_tick = self
+            .interpolation
+            .render_time()
+            .map(|render_time| (render_time / TIMESTEP).max(0.0) as Tick);
+        self.outgoing.push(Packet::PlayerInput {
+            sequence: input.sequence,
+            left: input.left,
+            right: input.right,
+            up: input.up,
+            view_tick,
+        });
+    }
+
+    fn capture_transforms(&mut self) {
+        let player_ship = self.player_ship();
+        let transforms = {
+            let entities = self.world.entities();
+            let networked = self.world.read_storage::<Networked>();
+            let transform = self.world.read_storage::<Transform>();
+            (&*entities, &transform)
+                .join()
+                .map(|(e, transform)| {
+                    let transform = if Some(e) == player_ship {
+                        // our own ship is offset by what's left of the last misprediction
+                        Transform {
+                            position: Vec2::new(
+                                transform.position.x + self.position_error.x,
+                                transform.position.y + self.position_error.y,
+                            ),
+                            scale: transform.scale,
+                            rotation: transform.rotation + self.rotation_error,
+                        }
+                    } else {
+                        networked
+                            .get(e)
+                            .and_then(|n| self.interpolation.transform(n.entity_id))
+                            .unwrap_or_else(|| transform.clone())
+                    };
+                    (e, transform)
+                })
+                .collect()
+        };
+        self.previous_transforms = mem::replace(&mut self.current_transforms, transforms);
+    }
+
     pub fn render(&mut self, renderer: &mut RenderInterface) -> Result<(), Error> {
+        // how far the time not yet simulated is into the next step
+        let alpha = (self.accumulator / TIMESTEP) as f32;
+
+        let entities = self.world.entities();
         let transform = self.world.read_storage::<Transform>();
         let sprite = self.world.read_storage::<Sprite>();
-        for (transform, sprite) in (&transform, &sprite).join() {
+        for (e, transform, sprite) in (&*entities, &transform, &sprite).join() {
+            let transform = match (
+                self.previous_transforms.get(&e),
+                self.current_transforms.get(&e),
+            ) {
+                (Some(previous), Some(current)) => previous.lerp(current, alpha),
+                (None, Some(current)) => current.clone(),
+                // not stepped yet, e.g. just created
+                _ => transform.clone(),
+            };
             renderer.draw_texture(
                 &sprite.texture,
                 transform.position,
@@ -117,3 +542,91 @@ impl GameClient {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game_server::GameServer;
+    use transport::MemoryServerTransport;
+
+    static IDLE: Controls = Controls {
+        left: false,
+        right: false,
+        up: false,
+    };
+    static FORWARD: Controls = Controls {
+        left: false,
+        right: false,
+        up: true,
+    };
+    // where the server spawns ships, facing along x
+    static SPAWN_X: f32 = 200.0;
+    // distance a ship moves forward in one step
+    static STEP_DISTANCE: f32 = 300.0 * TIMESTEP as f32;
+
+    /// A server and a client connected to it in memory, with the client's ship known to it.
+    fn connected_pair() -> (GameServer, GameClient) {
+        let server_transport = MemoryServerTransport::new();
+        let client_transport = server_transport.connect().unwrap();
+        let mut server = GameServer::new(Box::new(server_transport)).unwrap();
+        let mut client =
+            GameClient::new(Box::new(client_transport), ClientSettings::default()).unwrap();
+
+        for _ in 0..10 {
+            client.advance(TIMESTEP, IDLE).unwrap();
+            server.update(TIMESTEP).unwrap();
+        }
+        assert!(client.player_transform().is_some());
+        (server, client)
+    }
+
+    fn ship_x(client: &GameClient) -> f32 {
+        client.player_transform().unwrap().position.x
+    }
+
+    #[test]
+    fn own_ship_moves_without_waiting_for_the_server() {
+        let (_server, mut client) = connected_pair();
+
+        for _ in 0..5 {
+            client.advance(TIMESTEP, FORWARD).unwrap();
+        }
+        assert!((ship_x(&client) - (SPAWN_X + 5.0 * STEP_DISTANCE)).abs() < 0.1);
+        assert_eq!(client.pending_inputs.len(), 5);
+    }
+
+    #[test]
+    fn unacknowledged_inputs_are_replayed_after_a_correction() {
+        let (mut server, mut client) = connected_pair();
+
+        // the client runs a few steps ahead, so every update leaves inputs to replay
+        let mut sent = 0;
+        for _ in 0..3 {
+            client.advance(TIMESTEP, FORWARD).unwrap();
+            sent += 1;
+        }
+        // a misprediction, which the next update corrects
+        let ship = client.player_ship().unwrap();
+        client
+            .world
+            .write_storage::<Transform>()
+            .get_mut(ship)
+            .unwrap()
+            .position
+            .x += 40.0;
+
+        for round in 0..20 {
+            server.update(TIMESTEP).unwrap();
+            client.advance(TIMESTEP, FORWARD).unwrap();
+            sent += 1;
+
+            if round >= 2 {
+                assert!(!client.pending_inputs.is_empty());
+                let expected = SPAWN_X + sent as f32 * STEP_DISTANCE;
+                assert!((ship_x(&client) - expected).abs() < 0.1, "round {}", round);
+            }
+        }
+        // the correction is drawn smoothed out rather than snapped to
+        assert!(client.position_error.x > 0.0);
+    }
+}
@@ -1,142 +1,415 @@
 use failure::Error;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::ops::{BitAnd, Sub};
 
 use embla::math::Vec2;
 use specs::{Entity, Join, RunNow, World};
 
+use channel::ChannelEndpoint;
 use components;
 use components::{Networked, Player, Transform};
-use net::{ClientId, EntityId, NetComponentAdapter};
-use packets::{EntitiesStore, Packet};
+use net::{
+    ClientId, EntityId, EntityIdAllocator, NetComponentAdapter, ProtocolError, SnapshotBuffer,
+    SnapshotSequence, DELTA_HEADER_SIZE,
+};
+use packets;
+use packets::{EntitiesStore, InputSequence, Manifest, Packet, Tick, PROTOCOL_VERSION};
 use prefab;
 use prefab::{PlayerPrefab, Prefab};
 use systems::{MovementSystem, PlayerControlSystem};
+use transport::{Transport, TransportEvent};
 
 pub static TIMESTEP: f64 = 1.0 / 60.0;
+/// Most simulation steps run in one update when catching up, time beyond that is dropped so a
+/// slow frame can't snowball into ever longer ones.
+pub static MAX_STEPS_PER_UPDATE: u32 = 5;
+// updates are sent to clients every this many ticks
+static SEND_INTERVAL: Tick = 2;
+// clients are only told about entities within this distance of their ship
+static INTEREST_RADIUS: f32 = 800.0;
+// entities a client knows about are kept until they're this much further out, so entities on
+// the edge don't get destroyed and created over and over
+static INTEREST_HYSTERESIS: f32 = 1.25;
+// bytes of entity state each client is sent per tick, entities that don't fit are sent later
+static BYTES_PER_TICK: usize = 600;
+// rejected clients are disconnected after this many seconds, giving the rejection time to arrive
+static REJECT_GRACE_PERIOD: f64 = 1.0;
+static MAX_PLAYER_NAME_LENGTH: usize = 32;
+// ticks of transform history kept for lag compensation, hits are never resolved further back
+static HISTORY_TICKS: usize = 60;
 
+// inputs queued beyond this are dropped, so a client can't build up lag on the server
+static MAX_BUFFERED_INPUTS: usize = 8;
+
+#[derive(Clone, Copy)]
 struct ClientInput {
     left: bool,
     right: bool,
     up: bool,
+    view_tick: Option<Tick>,
 }
 
 struct ClientData {
+    channel: ChannelEndpoint,
     outgoing: Vec<Packet>,
+    player_name: Option<String>,
+    disconnect_at: Option<f64>,
     known_entities: HashSet<EntityId>,
     input: ClientInput,
+    // inputs are applied one per tick, in the order the client produced them
+    inputs: VecDeque<(InputSequence, ClientInput)>,
+    last_received_input: Option<InputSequence>,
+    last_processed_input: Option<InputSequence>,
+    reported_input: Option<InputSequence>,
     player_ship: Option<Entity>,
+
+    snapshots: SnapshotBuffer,
+    acked_snapshot: Option<SnapshotSequence>,
+    // grows for every update an entity's changes are left out of, so nothing starves
+    priorities: HashMap<EntityId, f32>,
 }
 
 pub struct GameServer {
+    transport: Box<Transport>,
+    time: f64,
+    // elapsed time not yet simulated
+    accumulator: f64,
+    // ticks simulated so far, the last snapshot sent was taken at this tick
+    tick: Tick,
+    // transforms of networked entities at the end of each recent tick, oldest first
+    history: VecDeque<(Tick, HashMap<Entity, Transform>)>,
     world: World,
     prefabs: prefab::Registry,
-    entity_id: u16,
+    net_entity_ids: EntityIdAllocator,
     net_adapter: NetComponentAdapter,
+    registry_checksum: u64,
+    snapshot_sequence: SnapshotSequence,
 
     clients: HashMap<ClientId, ClientData>,
+    // clients dropped for misbehaving or being unreachable during the last update, and why
+    dropped_clients: Vec<(ClientId, String)>,
 
     movement_system: MovementSystem,
     player_control_system: PlayerControlSystem,
 }
 
 impl GameServer {
-    pub fn new() -> Result<GameServer, Error> {
+    pub fn new(transport: Box<Transport>) -> Result<GameServer, Error> {
         let mut world = World::new();
         let mut net_adapter = NetComponentAdapter::new();
         components::register_components(&mut world, &mut net_adapter);
 
         let mut prefabs = prefab::Registry::new();
         prefab::register_prefabs(&mut prefabs);
+        let registry_checksum = packets::registry_checksum(&net_adapter, &prefabs);
 
         Ok(GameServer {
+            transport,
+            time: 0.0,
+            accumulator: 0.0,
+            tick: 0,
+            history: VecDeque::with_capacity(HISTORY_TICKS),
             world,
             prefabs,
-            entity_id: 0,
+            net_entity_ids: EntityIdAllocator::new(),
             net_adapter,
+            registry_checksum,
+            snapshot_sequence: 0,
 
             clients: HashMap::new(),
+            dropped_clients: Vec::new(),
 
             movement_system: MovementSystem::new(),
             player_control_system: PlayerControlSystem::new(),
         })
     }
 
-    pub fn add_client(&mut self, client_id: ClientId) {
+    fn add_client(&mut self, client_id: ClientId) {
         self.clients.insert(
             client_id,
             ClientData {
+                channel: ChannelEndpoint::new(),
                 outgoing: Vec::new(),
+                player_name: None,
+                disconnect_at: None,
                 known_entities: HashSet::new(),
                 input: ClientInput {
                     left: false,
                     right: false,
                     up: false,
+                    view_tick: None,
                 },
+                inputs: VecDeque::new(),
+                last_received_input: None,
+                last_processed_input: None,
+                reported_input: None,
                 player_ship: None,
+
+                snapshots: SnapshotBuffer::new(),
+                acked_snapshot: None,
+                priorities: HashMap::new(),
             },
         );
     }
 
-    pub fn handle_incoming(&mut self, client_id: ClientId, packet: &Packet) -> Result<(), Error> {
+    fn remove_client(&mut self, client_id: ClientId) -> Result<(), Error> {
+        if let Some(client_data) = self.clients.remove(&client_id) {
+            if let Some(ship) = client_data.player_ship {
+                self.destroy_net_entity(ship)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_incoming(&mut self, client_id: ClientId, packet: &Packet) -> Result<(), Error> {
         match *packet {
-            Packet::Connect => {
+            Packet::Connect {
+                protocol_version,
+                checksum,
+                ref player_name,
+            } => {
+                // a rejected client is only waiting to be disconnected, it doesn't get to retry
+                let client_data = self.client_mut(client_id)?;
+                if client_data.player_name.is_some() || client_data.disconnect_at.is_some() {
+                    return Err(ProtocolError::UnexpectedPacket("connected twice").into());
+                }
+
+                let player_name = player_name.trim();
+                let rejection = if protocol_version != PROTOCOL_VERSION {
+                    Some(format!(
+                        "protocol version {} is not supported, the server uses version {}",
+                        protocol_version, PROTOCOL_VERSION
+                    ))
+                } else if checksum != self.registry_checksum {
+                    Some("game data doesn't match the server's, the client is outdated".to_string())
+                } else if player_name.is_empty()
+                    || player_name.chars().count() > MAX_PLAYER_NAME_LENGTH
+                {
+                    Some(format!(
+                        "player name must be 1 to {} characters",
+                        MAX_PLAYER_NAME_LENGTH
+                    ))
+                } else {
+                    None
+                };
+                if let Some(reason) = rejection {
+                    let disconnect_at = self.time + REJECT_GRACE_PERIOD;
+                    let client_data = self.client_mut(client_id)?;
+                    client_data.outgoing.push(Packet::ConnectRejected { reason });
+                    client_data.disconnect_at = Some(disconnect_at);
+                    return Ok(());
+                }
+
                 let e = self.create_net_entity::<PlayerPrefab>()?;
                 self.world
                     .write_storage::<Transform>()
                     .get_mut(e)
                     .unwrap()
                     .position = Vec2::new(200.0, 200.0);
+                let player_entity = self
+                    .world
+                    .read_storage::<Networked>()
+                    .get(e)
+                    .unwrap()
+                    .entity_id;
+
+                let manifest = Manifest {
+                    components: self.net_adapter.manifest(),
+                    prefabs: self.prefabs.manifest(),
+                };
 
-                let mut client_data = self.clients.get_mut(&client_id).unwrap();
-                client_data.outgoing.push(Packet::Initialize);
+                let client_data = self.client_mut(client_id)?;
+                client_data.outgoing.push(Packet::Initialize {
+                    player_entity,
+                    manifest,
+                });
+                client_data.player_name = Some(player_name.to_string());
                 client_data.player_ship = Some(e);
             }
-            Packet::PlayerInput { left, right, up } => {
-                self.clients.get_mut(&client_id).unwrap().input = ClientInput { left, right, up };
+            Packet::PlayerInput {
+                sequence,
+                left,
+                right,
+                up,
+                view_tick,
+            } => {
+                let client_data = self.client_mut(client_id)?;
+                // drop inputs that arrive late or twice
+                if client_data.last_received_input.map(|s| sequence > s).unwrap_or(true) {
+                    client_data.last_received_input = Some(sequence);
+                    let input = ClientInput {
+                        left,
+                        right,
+                        up,
+                        view_tick,
+                    };
+                    client_data.inputs.push_back((sequence, input));
+                    while client_data.inputs.len() > MAX_BUFFERED_INPUTS {
+                        client_data.inputs.pop_front();
+                    }
+                }
+            }
+            Packet::Ping { time } => {
+                let tick = self.tick;
+                let client_data = self.client_mut(client_id)?;
+                client_data.outgoing.push(Packet::Pong { time, tick });
+            }
+            Packet::SnapshotAck(sequence) => {
+                let client_data = self.client_mut(client_id)?;
+                // acks can arrive out of order, only ever move the baseline forward. A snapshot
+                // that was never sent can't become the baseline either.
+                let newer = client_data.acked_snapshot.map(|s| sequence > s).unwrap_or(true);
+                if newer && client_data.snapshots.get(sequence).is_some() {
+                    client_data.acked_snapshot = Some(sequence);
+                }
             }
             _ => {
-                return Err(format_err!("server received unexpected packet"));
+                return Err(ProtocolError::UnexpectedPacket("only sent by the server").into());
             }
         }
 
         Ok(())
     }
 
-    pub fn take_outgoing(&mut self, client_id: &ClientId) -> Option<Vec<Packet>> {
+    /// Disconnects a client the server can't keep serving.
+    fn drop_client(&mut self, client_id: ClientId, reason: String) -> Result<(), Error> {
+        self.transport.disconnect(client_id)?;
+        self.remove_client(client_id)?;
+        self.dropped_clients.push((client_id, reason));
+
+        Ok(())
+    }
+
+    /// Clients dropped during the last update for misbehaving or being unreachable, with the
+    /// reason for each.
+    pub fn dropped_clients(&self) -> &[(ClientId, String)] {
+        &self.dropped_clients
+    }
+
+    fn poll_transport(&mut self) -> Result<(), Error> {
+        for event in self.transport.poll()? {
+            match event {
+                TransportEvent::Connected(client_id) => self.add_client(client_id),
+                TransportEvent::Disconnected(client_id) => self.remove_client(client_id)?,
+                TransportEvent::Received(client_id, data) => {
+                    if !self.clients.contains_key(&client_id) {
+                        continue;
+                    }
+                    if let Err(error) = self.receive(client_id, &data) {
+                        // a misbehaving client is dropped, anything else is our own problem
+                        if error.downcast_ref::<ProtocolError>().is_none() {
+                            return Err(error);
+                        }
+                        self.drop_client(client_id, error.to_string())?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self, client_id: ClientId, datagram: &[u8]) -> Result<(), Error> {
+        let messages = self.client_mut(client_id)?.channel.receive(datagram)?;
+        for message in messages {
+            let packet = Packet::decode(&message)?;
+            self.handle_incoming(client_id, &packet)?;
+        }
+
+        Ok(())
+    }
+
+    fn client_mut(&mut self, client_id: ClientId) -> Result<&mut ClientData, ProtocolError> {
         self.clients
             .get_mut(&client_id)
-            .map(|c| mem::replace(&mut c.outgoing, Vec::new()))
+            .ok_or(ProtocolError::UnknownClient(client_id))
     }
 
-    pub fn update(&mut self, _dt: f64) -> Result<(), Error> {
-        // send new entities to clients
-        let entity_ids = self.entity_ids();
-        for (_, mut client_data) in self.clients.iter_mut() {
-            client_data.known_entities = entity_ids.bitand(&client_data.known_entities);
+    fn flush_outgoing(&mut self) -> Result<(), Error> {
+        let mut lost = Vec::new();
+        for (client_id, client_data) in self.clients.iter_mut() {
+            for packet in mem::replace(&mut client_data.outgoing, Vec::new()) {
+                client_data.channel.send(packet.channel(), packet.encode()?);
+            }
+            for datagram in client_data.channel.flush(self.time)? {
+                // a connection the transport can't send on is as good as lost, it's no reason
+                // to stop serving everyone else
+                if let Err(error) = self.transport.send(*client_id, datagram) {
+                    lost.push((*client_id, error.to_string()));
+                    break;
+                }
+            }
+            if client_data.channel.is_lost() {
+                lost.push((*client_id, "connection lost".to_string()));
+            }
         }
-        let client_unknowns: HashMap<ClientId, HashSet<EntityId>> = self
+
+        for (client_id, reason) in lost {
+            if !self.clients.contains_key(&client_id) {
+                continue;
+            }
+            self.drop_client(client_id, reason)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, dt: f64) -> Result<(), Error> {
+        self.dropped_clients.clear();
+        self.time += dt;
+        self.transport.tick(dt)?;
+        self.poll_transport()?;
+
+        let time = self.time;
+        let expired: Vec<ClientId> = self
             .clients
             .iter()
-            .map(|(client_id, client_data)| {
-                (*client_id, entity_ids.sub(&client_data.known_entities))
+            .filter(|&(_, client_data)| {
+                client_data.disconnect_at.map(|t| time >= t).unwrap_or(false)
             })
+            .map(|(client_id, _)| *client_id)
             .collect();
-        for (client_id, unknown_entities) in client_unknowns {
-            let entities_store = self.store_net_entities(Some(&unknown_entities));
-            let client_data = self.clients.get_mut(&client_id).unwrap();
-            client_data
-                .outgoing
-                .push(Packet::CreateEntities(entities_store));
-            client_data.known_entities = entity_ids.clone();
+        for client_id in expired {
+            self.transport.disconnect(client_id)?;
+            self.remove_client(client_id)?;
         }
 
-        // game logic
+        self.accumulator += dt;
+        let mut steps = 0;
+        while self.accumulator >= TIMESTEP {
+            if steps == MAX_STEPS_PER_UPDATE {
+                // fallen too far behind, give up on the time that's left
+                self.accumulator = 0.0;
+                break;
+            }
+            self.step();
+            self.accumulator -= TIMESTEP;
+            steps += 1;
+
+            if self.tick % SEND_INTERVAL == 0 {
+                self.send_updates()?;
+            }
+        }
+
+        self.flush_outgoing()?;
+
+        Ok(())
+    }
+
+    /// Advances the simulation by one `TIMESTEP`, applying the next queued input of each client.
+    fn step(&mut self) {
         {
-            // set client inputs to their respective ship player components
+            // set client inputs to their respective ship player components, when no new input
+            // has arrived the previous one is repeated
             let mut player = self.world.write_storage::<Player>();
-            for (_, client_data) in self.clients.iter() {
+            for (_, client_data) in self.clients.iter_mut() {
+                if let Some((sequence, input)) = client_data.inputs.pop_front() {
+                    client_data.input = input;
+                    client_data.last_processed_input = Some(sequence);
+                }
+
                 if client_data.player_ship.is_none() {
                     continue;
                 }
@@ -151,29 +424,252 @@ impl GameServer {
 
         self.player_control_system.run_now(&self.world.res);
         self.movement_system.run_now(&self.world.res);
+        self.tick += 1;
 
-        // send new net deltas to clients
-        let component_delta = self.net_adapter.read_delta(&self.world, None);
-        for (_, client_data) in self.clients.iter_mut() {
+        let transforms = {
+            let entities = self.world.entities();
+            let networked = self.world.read_storage::<Networked>();
+            let transform = self.world.read_storage::<Transform>();
+            (&*entities, &networked, &transform)
+                .join()
+                .map(|(e, _, transform)| (e, transform.clone()))
+                .collect()
+        };
+        if self.history.len() >= HISTORY_TICKS {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.tick, transforms));
+    }
+
+    /// Casts a ray from `origin` along `direction` up to `range` against networked entities as
+    /// the given client saw them, rewound to the tick it was viewing when it sent the input
+    /// being applied. Entities count as circles of the given radius. Returns the closest entity
+    /// hit and its distance along the ray, the client's own ship is never hit.
+    pub fn hit_query(
+        &self,
+        client_id: ClientId,
+        origin: Vec2<f32>,
+        direction: Vec2<f32>,
+        range: f32,
+        radius: f32,
+    ) -> Option<(Entity, f32)> {
+        let client_data = self.clients.get(&client_id)?;
+        let length = (direction.x * direction.x + direction.y * direction.y).sqrt();
+        if length == 0.0 {
+            return None;
+        }
+        let direction = Vec2::new(direction.x / length, direction.y / length);
+
+        // clients without a view yet, or viewing further back than the history reaches, are
+        // resolved at the closest tick that is still known
+        let oldest = self.history.front()?.0;
+        let view_tick = client_data
+            .input
+            .view_tick
+            .unwrap_or(self.tick)
+            .max(oldest)
+            .min(self.tick);
+        let transforms = &self.history[(view_tick - oldest) as usize].1;
+
+        let entities = self.world.entities();
+        let mut closest: Option<(Entity, f32)> = None;
+        for (&e, transform) in transforms.iter() {
+            // entities destroyed since can't be hit anymore
+            if !entities.is_alive(e) || Some(e) == client_data.player_ship {
+                continue;
+            }
+
+            let to_target = Vec2::new(
+                transform.position.x - origin.x,
+                transform.position.y - origin.y,
+            );
+            let along = to_target.x * direction.x + to_target.y * direction.y;
+            let across = to_target.x * direction.y - to_target.y * direction.x;
+            if across.abs() > radius {
+                continue;
+            }
+            let half_chord = (radius * radius - across * across).sqrt();
+            // the circle is entirely behind the origin
+            if along + half_chord < 0.0 {
+                continue;
+            }
+            // distance to where the ray enters the circle, zero when it starts inside
+            let distance = (along - half_chord).max(0.0);
+            if distance > range {
+                continue;
+            }
+            if closest.map(|(_, d)| distance < d).unwrap_or(true) {
+                closest = Some((e, distance));
+            }
+        }
+        closest
+    }
+
+    /// Queues entity creation, destruction and the state of the current tick for every client.
+    fn send_updates(&mut self) -> Result<(), Error> {
+        // tell clients about entities that were destroyed or left their area of interest
+        let relevant_entities = self.relevant_entities();
+        for (client_id, client_data) in self.clients.iter_mut() {
+            let relevant = &relevant_entities[client_id];
+            let destroyed: Vec<EntityId> = client_data
+                .known_entities
+                .sub(relevant)
+                .into_iter()
+                .collect();
+            if !destroyed.is_empty() {
+                client_data.outgoing.push(Packet::DestroyEntities(destroyed));
+            }
+            client_data.known_entities = relevant.bitand(&client_data.known_entities);
+        }
+
+        // send new entities and ones that entered the area of interest to clients
+        let client_unknowns: HashMap<ClientId, HashSet<EntityId>> = self
+            .clients
+            .iter()
+            .map(|(client_id, client_data)| {
+                let relevant = &relevant_entities[client_id];
+                (*client_id, relevant.sub(&client_data.known_entities))
+            })
+            .collect();
+        for (client_id, unknown_entities) in client_unknowns {
+            if unknown_entities.is_empty() {
+                continue;
+            }
+            let entities_store = self.store_net_entities(Some(&unknown_entities))?;
+            let client_data = self.clients.get_mut(&client_id).unwrap();
             client_data
                 .outgoing
-                .push(Packet::Update(component_delta.clone()));
+                .push(Packet::CreateEntities(entities_store));
+            client_data.known_entities = relevant_entities[&client_id].clone();
+        }
+
+        // send new net deltas to clients, relative to the last snapshot each client acknowledged
+        let sequence = self.snapshot_sequence;
+        self.snapshot_sequence += 1;
+        let entity_priorities = self.entity_priorities();
+        let networked = self.world.read_storage::<Networked>();
+        for (_, client_data) in self.clients.iter_mut() {
+            let player_entity = client_data
+                .player_ship
+                .and_then(|e| networked.get(e))
+                .map(|n| n.entity_id);
+            let (baseline, delta, components) = {
+                let ClientData {
+                    ref snapshots,
+                    ref mut priorities,
+                    ..
+                } = *client_data;
+                let baseline = client_data
+                    .acked_snapshot
+                    .and_then(|s| snapshots.get(s).map(|components| (s, components)));
+                let mut delta = self.net_adapter.read_delta(
+                    &self.world,
+                    Some(&client_data.known_entities),
+                    baseline.map(|(_, components)| components),
+                )?;
+
+                // nothing changed since the acknowledged snapshot, nothing newer is in flight
+                // and the client knows about its latest processed input, so it's up to date
+                if delta.is_empty()
+                    && snapshots.latest() == client_data.acked_snapshot
+                    && client_data.reported_input == client_data.last_processed_input
+                {
+                    continue;
+                }
+
+                let sizes = self.net_adapter.entity_sizes(&delta);
+                let budget = BYTES_PER_TICK * SEND_INTERVAL as usize - DELTA_HEADER_SIZE;
+                let included = select_entities(
+                    priorities,
+                    &entity_priorities,
+                    player_entity,
+                    &sizes,
+                    budget,
+                );
+                let omitted: HashSet<EntityId> = sizes
+                    .keys()
+                    .filter(|entity_id| !included.contains(entity_id))
+                    .cloned()
+                    .collect();
+                delta.retain(&included);
+
+                // the client only ends up with the baseline state of entities left out, which is
+                // what the next delta has to be relative to
+                let mut components = self
+                    .net_adapter
+                    .net_store(&self.world, Some(&client_data.known_entities))?;
+                components.revert(&omitted, baseline.map(|(_, components)| components));
+
+                let delta = self.net_adapter.encode_delta(&delta);
+                (baseline.map(|(s, _)| s), delta, components)
+            };
+
+            let known_entities = &client_data.known_entities;
+            client_data
+                .priorities
+                .retain(|entity_id, _| known_entities.contains(entity_id));
+            client_data.snapshots.push(sequence, components);
+            client_data.outgoing.push(Packet::Update {
+                sequence,
+                tick: self.tick,
+                baseline,
+                last_input: client_data.last_processed_input,
+                delta,
+            });
+            client_data.reported_input = client_data.last_processed_input;
         }
 
         Ok(())
     }
 
-    fn entity_ids(&self) -> HashSet<EntityId> {
+    /// Position and prefab priority of every networked entity.
+    fn entity_priorities(&self) -> HashMap<EntityId, (Vec2<f32>, f32)> {
         let networked = self.world.read_storage::<Networked>();
-        networked.join().map(|n| n.entity_id).collect()
+        let transform = self.world.read_storage::<Transform>();
+        (&networked, &transform)
+            .join()
+            .map(|(n, t)| (n.entity_id, (t.position, self.prefabs.net_priority(n.prefab))))
+            .collect()
     }
 
-    fn create_net_entity<P: Prefab + 'static>(&mut self) -> Result<Entity, Error> {
-        // TODO: Reuse old IDs, this will eventually run out of IDs and error
-        let entity_id = self.entity_id;
-        self.entity_id += 1;
+    /// The entities each client should know about, the ones within the interest radius of its
+    /// ship. Clients without a ship don't get to know about any.
+    fn relevant_entities(&self) -> HashMap<ClientId, HashSet<EntityId>> {
+        let networked = self.world.read_storage::<Networked>();
+        let transform = self.world.read_storage::<Transform>();
 
+        self.clients
+            .iter()
+            .map(|(client_id, client_data)| {
+                let center = client_data
+                    .player_ship
+                    .and_then(|e| transform.get(e))
+                    .map(|t| t.position);
+                let relevant = match center {
+                    Some(center) => (&networked, &transform)
+                        .join()
+                        .filter(|&(n, t)| {
+                            let radius = if client_data.known_entities.contains(&n.entity_id) {
+                                INTEREST_RADIUS * INTEREST_HYSTERESIS
+                            } else {
+                                INTEREST_RADIUS
+                            };
+                            let dx = t.position.x - center.x;
+                            let dy = t.position.y - center.y;
+                            dx * dx + dy * dy <= radius * radius
+                        })
+                        .map(|(n, _)| n.entity_id)
+                        .collect(),
+                    None => HashSet::new(),
+                };
+                (*client_id, relevant)
+            })
+            .collect()
+    }
+
+    fn create_net_entity<P: Prefab + 'static>(&mut self) -> Result<Entity, Error> {
         let (e, prefab) = self.prefabs.create::<P>(&mut self.world)?;
+        let entity_id = self.net_entity_ids.allocate()?;
         self.world
             .write_storage::<Networked>()
             .insert(e, Networked { entity_id, prefab })?;
@@ -181,7 +677,32 @@ impl GameServer {
         Ok(e)
     }
 
-    fn store_net_entities(&mut self, entity_set: Option<&HashSet<EntityId>>) -> EntitiesStore {
+    /// Despawns a networked entity, clients that know about it are told to destroy it on the
+    /// next update.
+    pub fn destroy_net_entity(&mut self, entity: Entity) -> Result<(), Error> {
+        let entity_id = self
+            .world
+            .read_storage::<Networked>()
+            .get(entity)
+            .map(|n| n.entity_id)
+            .ok_or_else(|| format_err!("attempt to destroy entity that is not networked"))?;
+
+        for (_, client_data) in self.clients.iter_mut() {
+            if client_data.player_ship == Some(entity) {
+                client_data.player_ship = None;
+            }
+        }
+
+        self.world.delete_entity(entity)?;
+        self.net_entity_ids.free(entity_id);
+
+        Ok(())
+    }
+
+    fn store_net_entities(
+        &mut self,
+        entity_set: Option<&HashSet<EntityId>>,
+    ) -> Result<EntitiesStore, Error> {
         let entities = self
             .world
             .read_storage::<Networked>()
@@ -195,11 +716,285 @@ impl GameServer {
                 }
             })
             .collect();
-        let components = self.net_adapter.net_store(&self.world, entity_set);
+        let components = self.net_adapter.net_store(&self.world, entity_set)?;
 
-        EntitiesStore {
+        Ok(EntitiesStore {
             entities,
             components,
+        })
+    }
+}
+
+/// Picks the entities with changes to send to a client this update, given the encoded size of
+/// each, highest accumulated priority first until the byte budget is spent. The client's own
+/// ship is always sent, its prediction is reconciled against every update. Entities that are
+/// left out have their priority raised, by their prefab priority and more the closer they are
+/// to the ship.
+fn select_entities(
+    priorities: &mut HashMap<EntityId, f32>,
+    entity_priorities: &HashMap<EntityId, (Vec2<f32>, f32)>,
+    player_entity: Option<EntityId>,
+    sizes: &HashMap<EntityId, usize>,
+    budget: usize,
+) -> HashSet<EntityId> {
+    let center = player_entity
+        .and_then(|entity_id| entity_priorities.get(&entity_id))
+        .map(|&(position, _)| position);
+
+    let mut candidates: Vec<(EntityId, f32, usize)> = Vec::new();
+    for (&entity_id, &size) in sizes.iter() {
+        let (position, prefab_priority) = entity_priorities
+            .get(&entity_id)
+            .cloned()
+            .unwrap_or((Vec2::zero(), 1.0));
+        let proximity = match center {
+            Some(center) => {
+                let dx = position.x - center.x;
+                let dy = position.y - center.y;
+                1.0 - ((dx * dx + dy * dy).sqrt() / INTEREST_RADIUS).min(1.0) * 0.75
+            }
+            None => 1.0,
+        };
+        let priority = priorities.entry(entity_id).or_insert(0.0);
+        *priority += prefab_priority * proximity;
+        candidates.push((entity_id, *priority, size));
+    }
+    candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    let mut used = 0;
+    let mut included = HashSet::new();
+    if let Some(entity_id) = player_entity {
+        if let Some(&(_, _, size)) = candidates.iter().find(|c| c.0 == entity_id) {
+            used += size;
+            included.insert(entity_id);
+        }
+    }
+    for (entity_id, _, size) in candidates {
+        if !included.contains(&entity_id) && used + size <= budget {
+            used += size;
+            included.insert(entity_id);
+        }
+    }
+
+    for entity_id in included.iter() {
+        priorities.insert(*entity_id, 0.0);
+    }
+    included
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use components::Velocity;
+    use transport::MemoryServerTransport;
+
+    static CLIENT: ClientId = 0;
+
+    /// A server with one client and an entity moving along x by 5 px per tick from the origin,
+    /// stepped the given number of ticks.
+    fn moving_entity_server(ticks: u32) -> (GameServer, Entity) {
+        let mut server = GameServer::new(Box::new(MemoryServerTransport::new())).unwrap();
+        let e = server.create_net_entity::<PlayerPrefab>().unwrap();
+        server.world.write_storage::<Player>().remove(e);
+        server
+            .world
+            .write_storage::<Velocity>()
+            .insert(e, Velocity(Vec2::new(300.0, 0.0)))
+            .unwrap();
+        server.add_client(CLIENT);
+
+        for _ in 0..ticks {
+            server.step();
         }
+        (server, e)
+    }
+
+    fn set_view_tick(server: &mut GameServer, view_tick: Option<Tick>) {
+        server.clients.get_mut(&CLIENT).unwrap().input.view_tick = view_tick;
+    }
+
+    /// Casts a ray straight down through the given x.
+    fn hit_at(server: &GameServer, x: f32) -> Option<Entity> {
+        server
+            .hit_query(CLIENT, Vec2::new(x, -100.0), Vec2::new(0.0, 1.0), 200.0, 10.0)
+            .map(|(e, _)| e)
+    }
+
+    #[test]
+    fn hits_are_resolved_at_the_viewed_tick() {
+        let (mut server, e) = moving_entity_server(30);
+
+        set_view_tick(&mut server, Some(10));
+        assert_eq!(hit_at(&server, 50.0), Some(e));
+        assert_eq!(hit_at(&server, 150.0), None);
+
+        set_view_tick(&mut server, None);
+        assert_eq!(hit_at(&server, 50.0), None);
+        assert_eq!(hit_at(&server, 150.0), Some(e));
+    }
+
+    #[test]
+    fn view_ticks_are_clamped_to_the_history() {
+        let (mut server, e) = moving_entity_server(HISTORY_TICKS as u32 + 40);
+        let oldest = server.history.front().unwrap().0;
+        assert_eq!(oldest, 41);
+
+        set_view_tick(&mut server, Some(5));
+        assert_eq!(hit_at(&server, 25.0), None);
+        assert_eq!(hit_at(&server, oldest as f32 * 5.0), Some(e));
+
+        let tick = server.tick;
+        set_view_tick(&mut server, Some(tick + 100));
+        assert_eq!(hit_at(&server, tick as f32 * 5.0), Some(e));
+    }
+
+    #[test]
+    fn own_ship_is_never_hit() {
+        let (mut server, e) = moving_entity_server(10);
+        server.clients.get_mut(&CLIENT).unwrap().player_ship = Some(e);
+        assert_eq!(hit_at(&server, 50.0), None);
+    }
+
+    #[test]
+    fn rejected_clients_cannot_connect_again() {
+        let mut server = GameServer::new(Box::new(MemoryServerTransport::new())).unwrap();
+        server.add_client(CLIENT);
+        let connect = |checksum| Packet::Connect {
+            protocol_version: PROTOCOL_VERSION,
+            checksum,
+            player_name: "player".to_string(),
+        };
+
+        let checksum = server.registry_checksum;
+        server.handle_incoming(CLIENT, &connect(checksum + 1)).unwrap();
+        assert!(server.handle_incoming(CLIENT, &connect(checksum)).is_err());
+        assert!(server.clients[&CLIENT].player_ship.is_none());
+    }
+
+    #[test]
+    fn clients_that_cannot_be_sent_to_are_dropped() {
+        let mut server = GameServer::new(Box::new(MemoryServerTransport::new())).unwrap();
+        // never connected through the transport, so sending to it fails
+        server.add_client(CLIENT);
+        server.clients.get_mut(&CLIENT).unwrap().outgoing.push(Packet::ConnectRejected {
+            reason: "test".to_string(),
+        });
+
+        server.flush_outgoing().unwrap();
+        assert!(!server.clients.contains_key(&CLIENT));
+        assert_eq!(server.dropped_clients().len(), 1);
+        assert_eq!(server.dropped_clients()[0].0, CLIENT);
+    }
+
+    fn entity_id(index: u16) -> EntityId {
+        EntityId {
+            index,
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn selection_stops_at_the_budget_in_priority_order() {
+        let mut priorities = HashMap::new();
+        let entity_priorities = (0..3)
+            .map(|i| (entity_id(i), (Vec2::zero(), i as f32 + 1.0)))
+            .collect();
+        let sizes = (0..3).map(|i| (entity_id(i), 100)).collect();
+
+        let included = select_entities(&mut priorities, &entity_priorities, None, &sizes, 250);
+        assert_eq!(included, vec![entity_id(1), entity_id(2)].into_iter().collect());
+    }
+
+    #[test]
+    fn entities_left_out_build_up_priority_until_sent() {
+        let mut priorities = HashMap::new();
+        let entity_priorities = vec![
+            (entity_id(0), (Vec2::zero(), 1.5)),
+            (entity_id(1), (Vec2::zero(), 1.0)),
+        ].into_iter()
+            .collect();
+        let sizes = vec![(entity_id(0), 100), (entity_id(1), 100)]
+            .into_iter()
+            .collect();
+
+        let first = select_entities(&mut priorities, &entity_priorities, None, &sizes, 100);
+        assert_eq!(first, vec![entity_id(0)].into_iter().collect());
+        assert_eq!(priorities[&entity_id(0)], 0.0);
+        assert_eq!(priorities[&entity_id(1)], 1.0);
+
+        let second = select_entities(&mut priorities, &entity_priorities, None, &sizes, 100);
+        assert_eq!(second, vec![entity_id(1)].into_iter().collect());
+    }
+
+    #[test]
+    fn player_ship_is_always_selected() {
+        let mut priorities = HashMap::new();
+        let entity_priorities = vec![
+            (entity_id(0), (Vec2::zero(), 0.1)),
+            (entity_id(1), (Vec2::zero(), 10.0)),
+        ].into_iter()
+            .collect();
+        let sizes = vec![(entity_id(0), 500), (entity_id(1), 100)]
+            .into_iter()
+            .collect();
+
+        let included = select_entities(
+            &mut priorities,
+            &entity_priorities,
+            Some(entity_id(0)),
+            &sizes,
+            100,
+        );
+        assert_eq!(included, vec![entity_id(0)].into_iter().collect());
+    }
+
+    /// Ids in the entity creation and destruction packets queued for the client, which are
+    /// taken out of its queue.
+    fn take_created_and_destroyed(server: &mut GameServer) -> (Vec<EntityId>, Vec<EntityId>) {
+        let mut created = Vec::new();
+        let mut destroyed = Vec::new();
+        let client_data = server.clients.get_mut(&CLIENT).unwrap();
+        for packet in mem::replace(&mut client_data.outgoing, Vec::new()) {
+            match packet {
+                Packet::CreateEntities(store) => {
+                    created.extend(store.entities.into_iter().map(|(entity_id, _)| entity_id))
+                }
+                Packet::DestroyEntities(entity_ids) => destroyed.extend(entity_ids),
+                _ => {}
+            }
+        }
+        (created, destroyed)
+    }
+
+    #[test]
+    fn entities_leaving_the_area_of_interest_are_destroyed_past_the_hysteresis() {
+        let mut server = GameServer::new(Box::new(MemoryServerTransport::new())).unwrap();
+        server.add_client(CLIENT);
+        let ship = server.create_net_entity::<PlayerPrefab>().unwrap();
+        server.clients.get_mut(&CLIENT).unwrap().player_ship = Some(ship);
+        let other = server.create_net_entity::<PlayerPrefab>().unwrap();
+        let other_id = server.world.read_storage::<Networked>().get(other).unwrap().entity_id;
+        let move_other = |server: &mut GameServer, x: f32| {
+            server.world.write_storage::<Transform>().get_mut(other).unwrap().position =
+                Vec2::new(x, 0.0);
+        };
+
+        move_other(&mut server, 100.0);
+        server.send_updates().unwrap();
+        let (created, _) = take_created_and_destroyed(&mut server);
+        assert!(created.contains(&other_id));
+
+        // outside the radius, but not far enough to be dropped yet
+        move_other(&mut server, INTEREST_RADIUS * (1.0 + INTEREST_HYSTERESIS) / 2.0);
+        server.send_updates().unwrap();
+        assert_eq!(take_created_and_destroyed(&mut server), (vec![], vec![]));
+
+        move_other(&mut server, INTEREST_RADIUS * INTEREST_HYSTERESIS + 10.0);
+        server.send_updates().unwrap();
+        assert_eq!(take_created_and_destroyed(&mut server), (vec![], vec![other_id]));
+
+        move_other(&mut server, 100.0);
+        server.send_updates().unwrap();
+        assert_eq!(take_created_and_destroyed(&mut server), (vec![other_id], vec![]));
     }
 }
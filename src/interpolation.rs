@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+
+use components::Transform;
+use net::EntityId;
+
+/// Buffers server transforms of entities that aren't predicted locally. They are drawn a fixed
+/// delay behind the estimated server clock, interpolated between the two snapshots around that
+/// time, so a late or lost snapshot doesn't make them jump.
+pub struct Interpolation {
+    delay: f64,
+    render_time: Option<f64>,
+    snapshots: VecDeque<(f64, HashMap<EntityId, Transform>)>,
+}
+
+impl Interpolation {
+    /// The delay is in seconds, it should cover a few server updates to hide jitter and loss.
+    pub fn new(delay: f64) -> Interpolation {
+        Interpolation {
+            delay,
+            render_time: None,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Adds the transforms from a server snapshot taken at the given server time. Snapshots
+    /// older than the latest one are ignored.
+    pub fn push(&mut self, time: f64, transforms: HashMap<EntityId, Transform>) {
+        if self.snapshots.back().map(|&(t, _)| time <= t).unwrap_or(false) {
+            return;
+        }
+        self.snapshots.push_back((time, transforms));
+    }
+
+    /// Sets the render time to the delay behind the estimated current server time, and forgets
+    /// snapshots that are no longer needed.
+    pub fn advance(&mut self, server_time: f64) {
+        let render_time = server_time - self.delay;
+        self.render_time = Some(render_time);
+
+        // keep the last snapshot at or before the render time to interpolate from
+        while self.snapshots.len() > 1 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Server time other entities are currently drawn at.
+    pub fn render_time(&self) -> Option<f64> {
+        self.render_time
+    }
+
+    /// The transform of an entity at the current render time, or `None` if it's not in the
+    /// buffered snapshots yet.
+    pub fn transform(&self, entity_id: EntityId) -> Option<Transform> {
+        let render_time = self.render_time?;
+
+        let mut snapshots = self.snapshots.iter();
+        let &(from_time, ref from) = snapshots.next()?;
+        let from = match from.get(&entity_id) {
+            Some(from) => from,
+            // the entity only appears in a later snapshot, show it as soon as it's there
+            None => {
+                return self
+                    .snapshots
+                    .iter()
+                    .filter_map(|&(_, ref transforms)| transforms.get(&entity_id))
+                    .next()
+                    .cloned()
+            }
+        };
+
+        match snapshots.next() {
+            Some(&(to_time, ref to)) if render_time > from_time => match to.get(&entity_id) {
+                Some(to) => {
+                    let t = ((render_time - from_time) / (to_time - from_time)).min(1.0);
+                    Some(from.lerp(to, t as f32))
+                }
+                None => Some(from.clone()),
+            },
+            _ => Some(from.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embla::math::Vec2;
+
+    static DELAY: f64 = 0.1;
+
+    fn entity_id(index: u16) -> EntityId {
+        EntityId {
+            index,
+            generation: 0,
+        }
+    }
+
+    fn at_x(x: f32) -> Transform {
+        Transform {
+            position: Vec2::new(x, 0.0),
+            ..Transform::default()
+        }
+    }
+
+    fn snapshot(entities: &[(u16, f32)]) -> HashMap<EntityId, Transform> {
+        entities
+            .iter()
+            .map(|&(index, x)| (entity_id(index), at_x(x)))
+            .collect()
+    }
+
+    fn x_at(interpolation: &Interpolation, index: u16) -> Option<f32> {
+        interpolation
+            .transform(entity_id(index))
+            .map(|transform| transform.position.x)
+    }
+
+    #[test]
+    fn blends_between_the_snapshots_around_the_render_time() {
+        let mut interpolation = Interpolation::new(DELAY);
+        interpolation.push(1.0, snapshot(&[(0, 0.0)]));
+        interpolation.push(1.1, snapshot(&[(0, 10.0)]));
+        assert_eq!(x_at(&interpolation, 0), None);
+
+        interpolation.advance(1.025 + DELAY);
+        assert!((x_at(&interpolation, 0).unwrap() - 2.5).abs() < 0.01);
+        interpolation.advance(1.075 + DELAY);
+        assert!((x_at(&interpolation, 0).unwrap() - 7.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn holds_the_newest_snapshot_once_past_it() {
+        let mut interpolation = Interpolation::new(DELAY);
+        interpolation.push(1.0, snapshot(&[(0, 0.0)]));
+        interpolation.push(1.1, snapshot(&[(0, 10.0)]));
+
+        interpolation.advance(2.0);
+        assert_eq!(x_at(&interpolation, 0), Some(10.0));
+    }
+
+    #[test]
+    fn entities_only_in_a_later_snapshot_are_shown_right_away() {
+        let mut interpolation = Interpolation::new(DELAY);
+        interpolation.push(1.0, snapshot(&[(0, 0.0)]));
+        interpolation.push(1.1, snapshot(&[(0, 10.0), (1, 50.0)]));
+
+        interpolation.advance(1.05 + DELAY);
+        assert_eq!(x_at(&interpolation, 1), Some(50.0));
+        assert_eq!(x_at(&interpolation, 2), None);
+    }
+
+    #[test]
+    fn out_of_order_snapshots_are_ignored() {
+        let mut interpolation = Interpolation::new(DELAY);
+        interpolation.push(1.0, snapshot(&[(0, 0.0)]));
+        interpolation.push(1.1, snapshot(&[(0, 10.0)]));
+        interpolation.push(1.05, snapshot(&[(0, 100.0)]));
+
+        interpolation.advance(1.05 + DELAY);
+        assert!((x_at(&interpolation, 0).unwrap() - 5.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,40 @@
+//! The game itself, split from the binary so tools like the packet fuzzer can link to it.
+
+extern crate bincode;
+extern crate embla;
+#[macro_use]
+extern crate failure;
+#[cfg(target_arch = "wasm32")]
+extern crate js_sys;
+#[macro_use]
+extern crate net_derive;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate specs;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate tungstenite;
+#[cfg(target_arch = "wasm32")]
+extern crate wasm_bindgen;
+#[cfg(target_arch = "wasm32")]
+extern crate web_sys;
+
+pub mod application;
+pub mod bits;
+pub mod channel;
+pub mod client_application;
+pub mod client_server_application;
+pub mod clock;
+pub mod components;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dedicated_server;
+pub mod game_client;
+pub mod game_server;
+pub mod interpolation;
+pub mod net;
+pub mod packets;
+pub mod prefab;
+pub mod render_interface;
+pub mod renderer;
+pub mod systems;
+pub mod transport;
@@ -1,30 +1,149 @@
-extern crate bincode;
 extern crate embla;
 #[macro_use]
 extern crate failure;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate specs;
-
-mod client_server_application;
-mod components;
-mod game_client;
-mod game_server;
-mod net;
-mod packets;
-mod prefab;
-mod render_interface;
-mod renderer;
-mod systems;
+extern crate game;
+#[cfg(target_arch = "wasm32")]
+extern crate web_sys;
+
+use failure::Error;
 
 use embla::math::Vec2;
 use embla::window::WindowSettings;
 
-pub use client_server_application::ClientServerApplication;
+use game::application::Application;
+use game::client_application::ClientApplication;
+#[cfg(not(target_arch = "wasm32"))]
+use game::dedicated_server;
+use game::game_client::ClientSettings;
+use game::transport::{SimulatedTransport, SimulatorConfig, Transport};
+
+pub use game::client_server_application::ClientServerApplication;
+
+/// Returns the value following the given command line flag, if any.
+#[cfg(not(target_arch = "wasm32"))]
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn server_transport() -> Result<Option<Box<Transport>>, Error> {
+    use game::transport::{UdpServerTransport, WebSocketServerTransport};
+
+    if let Some(address) = arg_value("--server") {
+        let transport = UdpServerTransport::bind(address)?;
+        println!("listening for udp clients on {}", transport.local_addr()?);
+        return Ok(Some(Box::new(transport)));
+    }
+    if let Some(address) = arg_value("--websocket-server") {
+        let transport = WebSocketServerTransport::bind(address)?;
+        println!("listening for websocket clients on {}", transport.local_addr()?);
+        return Ok(Some(Box::new(transport)));
+    }
+
+    Ok(None)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn client_transport() -> Result<Option<Box<Transport>>, Error> {
+    use game::transport::{UdpClientTransport, WebSocketClientTransport};
+
+    if let Some(address) = arg_value("--connect") {
+        return Ok(Some(Box::new(UdpClientTransport::connect(address)?)));
+    }
+    if let Some(address) = arg_value("--connect-websocket") {
+        return Ok(Some(Box::new(WebSocketClientTransport::connect(&address)?)));
+    }
+
+    Ok(None)
+}
+
+/// `--simulate <settings>` applies simulated network conditions to the client's connection, see
+/// `SimulatorConfig::parse` for the settings.
+#[cfg(not(target_arch = "wasm32"))]
+fn simulator_config() -> Result<Option<SimulatorConfig>, Error> {
+    match arg_value("--simulate") {
+        Some(settings) => Ok(Some(SimulatorConfig::parse(&settings)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn simulator_config() -> Result<Option<SimulatorConfig>, Error> {
+    Ok(None)
+}
+
+/// `--interpolation-delay <ms>` sets how far in the past other entities are drawn and
+/// `--name <name>` the name to play as.
+#[cfg(not(target_arch = "wasm32"))]
+fn client_settings() -> Result<ClientSettings, Error> {
+    let mut settings = ClientSettings::default();
+    if let Some(name) = arg_value("--name") {
+        settings.player_name = name;
+    }
+    if let Some(delay) = arg_value("--interpolation-delay") {
+        let delay: f64 = delay
+            .parse()
+            .map_err(|_| format_err!("invalid interpolation delay '{}'", delay))?;
+        settings.interpolation_delay = delay / 1000.0;
+    }
+
+    Ok(settings)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn client_settings() -> Result<ClientSettings, Error> {
+    Ok(ClientSettings::default())
+}
+
+/// In the browser the server to connect to is given in the page url, as `?connect=<address>`.
+#[cfg(target_arch = "wasm32")]
+fn client_transport() -> Result<Option<Box<Transport>>, Error> {
+    use game::transport::WebSocketClientTransport;
+
+    let search = web_sys::window()
+        .ok_or_else(|| format_err!("no window"))?
+        .location()
+        .search()
+        .map_err(|e| format_err!("failed to read page url: {:?}", e))?;
+    let address = search
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("connect"), Some(address)) => Some(address.to_string()),
+                _ => None,
+            }
+        })
+        .next();
+
+    match address {
+        Some(address) => Ok(Some(Box::new(WebSocketClientTransport::connect(&address)?))),
+        None => Ok(None),
+    }
+}
 
 pub fn main() {
-    embla::init(|mut context| {
+    // natively, `--server <address>` and `--websocket-server <address>` run a headless server and
+    // `--connect <address>` and `--connect-websocket <address>` a client playing on one. Without
+    // any of them the client plays on a server running in the same process.
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if let Some(transport) = server_transport().unwrap() {
+            dedicated_server::run(transport).unwrap();
+            return;
+        }
+    }
+    let simulator = simulator_config().unwrap();
+    let mut settings = Some(client_settings().unwrap());
+    let mut client_transport = client_transport().unwrap().map(|transport| match simulator {
+        Some(config) => Box::new(SimulatedTransport::new(transport, config)) as Box<Transport>,
+        None => transport,
+    });
+
+    embla::init(move |mut context| {
         let window = context
             .window(
                 WindowSettings::new()
@@ -34,7 +153,13 @@ pub fn main() {
             )
             .unwrap();
 
-        let mut application = ClientServerApplication::new(window).unwrap();
+        let settings = settings.take().unwrap();
+        let mut application: Box<Application> = match client_transport.take() {
+            Some(transport) => {
+                Box::new(ClientApplication::new(window, transport, settings).unwrap())
+            }
+            None => Box::new(ClientServerApplication::new(window, simulator, settings).unwrap()),
+        };
         move |dt, input| {
             application.update(dt, input)?;
 
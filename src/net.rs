@@ -1,155 +1,879 @@
+use failure::Error;
 use std::any::TypeId;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
+use bits::{BitReader, BitWriter};
 use components::Networked;
 
-use specs::{Component, Join, World};
+use embla::math::Vec2;
+use specs::{Component, Entity, Join, World};
 
 pub type ClientId = u8;
 
-pub type EntityId = u16;
+/// Data received from a peer that can't be accepted, caused by a buggy, outdated or hostile peer
+/// rather than by anything local. The server drops clients sending it instead of stopping.
+#[derive(Debug, Fail)]
+pub enum ProtocolError {
+    #[fail(display = "malformed data: {}", _0)]
+    Malformed(String),
+    #[fail(display = "read past the end of bit packed data")]
+    Truncated,
+    #[fail(display = "unregistered net component {}", _0)]
+    UnknownComponent(u8),
+    #[fail(display = "unregistered prefab {}", _0)]
+    UnknownPrefab(u8),
+    #[fail(display = "received components for unknown entities {:?}", _0)]
+    UnknownEntities(Vec<EntityId>),
+    #[fail(display = "unexpected packet, {}", _0)]
+    UnexpectedPacket(&'static str),
+    #[fail(display = "unknown client {}", _0)]
+    UnknownClient(ClientId),
+}
+
+/// Identifies a networked entity across server and clients. Indices are reused once an entity
+/// is destroyed, the generation tells apart entities that have shared the same index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntityId {
+    pub index: u16,
+    pub generation: u16,
+}
+
+pub struct EntityIdAllocator {
+    generations: Vec<u16>,
+    // freed indices are reused oldest first, to make stale ids as unlikely as possible
+    free: VecDeque<u16>,
+}
+
+impl EntityIdAllocator {
+    pub fn new() -> EntityIdAllocator {
+        EntityIdAllocator {
+            generations: Vec::new(),
+            free: VecDeque::new(),
+        }
+    }
+
+    pub fn allocate(&mut self) -> Result<EntityId, Error> {
+        if let Some(index) = self.free.pop_front() {
+            return Ok(EntityId {
+                index,
+                generation: self.generations[index as usize],
+            });
+        }
+
+        if self.generations.len() > std::u16::MAX as usize {
+            return Err(format_err!("ran out of net entity ids"));
+        }
+        let index = self.generations.len() as u16;
+        self.generations.push(0);
+
+        Ok(EntityId {
+            index,
+            generation: 0,
+        })
+    }
+
+    pub fn free(&mut self, entity_id: EntityId) {
+        if let Some(generation) = self.generations.get_mut(entity_id.index as usize) {
+            // ignore ids that have already been freed
+            if *generation == entity_id.generation {
+                *generation = generation.wrapping_add(1);
+                self.free.push_back(entity_id.index);
+            }
+        }
+    }
+}
+
+pub type SnapshotSequence = u32;
+
+// number of sent/received snapshots kept around to be used as delta baselines
+static SNAPSHOT_BUFFER_SIZE: usize = 32;
+/// Encoded size in bytes of the component and entity counts every packed delta starts with.
+pub static DELTA_HEADER_SIZE: usize = 3;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ComponentStore(HashMap<NetComponentIndex, HashMap<EntityId, Vec<u8>>>);
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct ComponentDelta(HashMap<NetComponentIndex, HashMap<EntityId, Vec<u8>>>);
+impl ComponentStore {
+    pub fn get(&self, component_index: NetComponentIndex, entity_id: EntityId) -> Option<&[u8]> {
+        self.0
+            .get(&component_index)
+            .and_then(|store| store.get(&entity_id))
+            .map(|data| data.as_slice())
+    }
 
+    /// Sets the given entities back to their state in the baseline, entities the baseline
+    /// doesn't have are removed.
+    pub fn revert(&mut self, entity_ids: &HashSet<EntityId>, baseline: Option<&ComponentStore>) {
+        for (component_index, store) in self.0.iter_mut() {
+            for entity_id in entity_ids {
+                match baseline.and_then(|b| b.get(*component_index, *entity_id)) {
+                    Some(data) => {
+                        store.insert(*entity_id, data.to_vec());
+                    }
+                    None => {
+                        store.remove(entity_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Changed component fields by entity, bit packed into a single stream for sending with
+/// `NetComponentAdapter::encode_delta`.
+#[derive(Clone)]
+pub struct ComponentDelta(HashMap<NetComponentIndex, HashMap<EntityId, BitWriter>>);
+
+impl ComponentDelta {
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|entities| entities.is_empty())
+    }
+
+    /// Drops all entities from the delta but the given ones.
+    pub fn retain(&mut self, entity_ids: &HashSet<EntityId>) {
+        for store in self.0.values_mut() {
+            store.retain(|entity_id, _| entity_ids.contains(entity_id));
+        }
+        self.0.retain(|_, store| !store.is_empty());
+    }
+}
+
+/// Ring of the most recent snapshots, either sent to or received from a peer. Deltas are always
+/// encoded relative to a snapshot both sides are known to have.
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(SnapshotSequence, ComponentStore)>,
+}
+
+impl SnapshotBuffer {
+    pub fn new() -> SnapshotBuffer {
+        SnapshotBuffer {
+            snapshots: VecDeque::with_capacity(SNAPSHOT_BUFFER_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, sequence: SnapshotSequence, components: ComponentStore) {
+        if self.snapshots.len() >= SNAPSHOT_BUFFER_SIZE {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((sequence, components));
+    }
+
+    pub fn get(&self, sequence: SnapshotSequence) -> Option<&ComponentStore> {
+        self.snapshots
+            .iter()
+            .find(|&&(s, _)| s == sequence)
+            .map(|&(_, ref components)| components)
+    }
+
+    pub fn latest(&self) -> Option<SnapshotSequence> {
+        self.snapshots.back().map(|&(sequence, _)| sequence)
+    }
+}
+
+/// Replicated component state. Usually derived with `#[derive(NetComponent)]`, see the
+/// `net_derive` crate for the attributes it takes.
 pub trait NetComponent {
-    fn net_store(&self) -> Vec<u8>;
-    fn net_load(&mut self, data: &[u8]);
+    /// Describes the encoding of the component, peers whose schemas differ can't exchange it.
+    fn net_schema() -> String
+    where
+        Self: Sized;
+
+    fn net_store(&self) -> Result<Vec<u8>, Error>;
+    fn net_load(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Encodes what changed since the baseline, given as written by `net_store`. Without a
+    /// baseline everything is included.
+    fn read_delta(&self, baseline: Option<&[u8]>) -> Result<BitWriter, Error>;
+    /// Applies a delta on top of the baseline state the component was loaded with.
+    fn write_delta(&mut self, data: &[u8]) -> Result<(), Error>;
+    /// Reads past a delta, to find where the next one starts in a packed stream.
+    fn skip_delta(reader: &mut BitReader) -> Result<(), Error>
+    where
+        Self: Sized;
+}
+
+/// A field of a derived `NetComponent`, written at full precision.
+pub trait NetField: Sized {
+    fn write(&self, writer: &mut BitWriter);
+    fn read(reader: &mut BitReader) -> Result<Self, Error>;
+}
+
+impl NetField for bool {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bool(*self);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        reader.read_bool()
+    }
+}
 
-    fn read_delta(&self) -> Vec<u8>;
-    fn write_delta(&mut self, data: &[u8]);
+impl NetField for u8 {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits(*self as u32, 8);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        Ok(reader.read_bits(8)? as u8)
+    }
+}
+
+impl NetField for u16 {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits(*self as u32, 16);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        Ok(reader.read_bits(16)? as u16)
+    }
+}
+
+impl NetField for u32 {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits(*self, 32);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        reader.read_bits(32)
+    }
+}
+
+impl NetField for f32 {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_f32(*self);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        reader.read_f32()
+    }
+}
+
+impl NetField for Vec2<f32> {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_f32(self.x);
+        writer.write_f32(self.y);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        Ok(Vec2::new(reader.read_f32()?, reader.read_f32()?))
+    }
+}
+
+impl NetField for EntityId {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.index as u32, 16);
+        writer.write_bits(self.generation as u32, 16);
+    }
+    fn read(reader: &mut BitReader) -> Result<Self, Error> {
+        Ok(EntityId {
+            index: reader.read_bits(16)? as u16,
+            generation: reader.read_bits(16)? as u16,
+        })
+    }
+}
+
+/// A field of a derived `NetComponent` that can be rounded to fewer bits within a range, with
+/// `#[net(quantize = "<min>, <max>, <bits>")]`.
+pub trait NetQuantize: Sized {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, bits: u32);
+    fn read_quantized(reader: &mut BitReader, min: f32, max: f32, bits: u32)
+        -> Result<Self, Error>;
+}
+
+impl NetQuantize for f32 {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, bits: u32) {
+        writer.write_quantized(*self, min, max, bits);
+    }
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        bits: u32,
+    ) -> Result<Self, Error> {
+        reader.read_quantized(min, max, bits)
+    }
+}
+
+/// Both coordinates are quantized to the same range.
+impl NetQuantize for Vec2<f32> {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, bits: u32) {
+        writer.write_quantized(self.x, min, max, bits);
+        writer.write_quantized(self.y, min, max, bits);
+    }
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        bits: u32,
+    ) -> Result<Self, Error> {
+        Ok(Vec2::new(
+            reader.read_quantized(min, max, bits)?,
+            reader.read_quantized(min, max, bits)?,
+        ))
+    }
+}
+
+/// Things registered under a name on both peers, such as net components or prefabs, and
+/// referred to by index when sent. Names have to be the same across builds, the order they're
+/// registered in needn't be, indices are mapped between peers by name once the peer's manifest
+/// is known.
+pub struct NamedIndices {
+    // what is registered, for messages
+    kind: &'static str,
+    names: Vec<&'static str>,
+    // the peer's indices mapped to ours, the peer uses the same ones until its manifest is known
+    remote_indices: Option<HashMap<u8, u8>>,
+}
+
+impl NamedIndices {
+    pub fn new(kind: &'static str) -> NamedIndices {
+        NamedIndices {
+            kind,
+            names: Vec::new(),
+            remote_indices: None,
+        }
+    }
+
+    /// Adds a name, returning its index.
+    pub fn register(&mut self, name: &'static str) -> u8 {
+        // counts of registered things are sent in a byte
+        if self.names.len() >= std::u8::MAX as usize {
+            panic!("max number of {}s is {}", self.kind, std::u8::MAX);
+        }
+        if self.names.contains(&name) {
+            panic!("{} name '{}' already registered", self.kind, name);
+        }
+        self.names.push(name);
+        (self.names.len() - 1) as u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Registered names, in the order of their indices.
+    pub fn names(&self) -> &[&'static str] {
+        &self.names
+    }
+
+    pub fn manifest(&self) -> Vec<String> {
+        self.names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// Sets the peer's names, in the order of its indices. Names we don't know about are an
+    /// error.
+    pub fn set_remote_manifest(&mut self, manifest: &[String]) -> Result<(), Error> {
+        let mut remote_indices = HashMap::new();
+        for (remote_index, name) in manifest.iter().enumerate() {
+            let index = self
+                .names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| format_err!("peer has unknown {} '{}'", self.kind, name))?;
+            remote_indices.insert(remote_index as u8, index as u8);
+        }
+        self.remote_indices = Some(remote_indices);
+
+        Ok(())
+    }
+
+    /// Our index of an index received from the peer, if it's one we know.
+    pub fn local_index(&self, remote_index: u8) -> Option<u8> {
+        let index = match self.remote_indices {
+            Some(ref remote_indices) => remote_indices.get(&remote_index).cloned(),
+            None => Some(remote_index),
+        };
+        index.filter(|&index| (index as usize) < self.names.len())
+    }
 }
 
 type NetComponentIndex = u8;
-static NET_COMPONENT_MAX: usize = std::u8::MAX as usize;
 
 type PackerFunction = Box<
     Fn(
         &World,
         Option<&HashSet<EntityId>>,
-        &Fn(&NetComponent) -> Vec<u8>,
-    ) -> HashMap<EntityId, Vec<u8>>,
+        &mut FnMut(EntityId, &NetComponent) -> Result<(), Error>,
+    ) -> Result<(), Error>,
+>;
+type LoaderFunction = Box<
+    Fn(
+        &World,
+        &[(EntityId, Entity)],
+        &mut FnMut(EntityId, &mut NetComponent) -> Result<(), Error>,
+    ) -> Result<(), Error>,
 >;
-type LoaderFunction = Box<Fn(&World, HashMap<EntityId, Vec<u8>>, &Fn(&mut NetComponent, &[u8]))>;
+type SkipperFunction = fn(&mut BitReader) -> Result<(), Error>;
 
 pub struct NetComponentAdapter {
-    index: HashMap<TypeId, u8>,
-    next_index: u8,
+    index: HashMap<TypeId, NetComponentIndex>,
+    indices: NamedIndices,
+    // schemas of the registered components, by index
+    schemas: Vec<String>,
 
     packers: HashMap<NetComponentIndex, PackerFunction>,
     loaders: HashMap<NetComponentIndex, LoaderFunction>,
+    skippers: HashMap<NetComponentIndex, SkipperFunction>,
 }
 
 impl NetComponentAdapter {
     pub fn new() -> Self {
         NetComponentAdapter {
             index: HashMap::new(),
-            next_index: 0,
+            indices: NamedIndices::new("net component"),
+            schemas: Vec::new(),
 
             packers: HashMap::new(),
             loaders: HashMap::new(),
+            skippers: HashMap::new(),
         }
     }
 
-    pub fn register_component<C>(&mut self)
+    /// Registers a component under its name, see `NamedIndices`.
+    pub fn register_component<C>(&mut self, name: &'static str)
     where
         C: Component + NetComponent + 'static,
     {
-        if (self.next_index as usize) + 1 > NET_COMPONENT_MAX {
-            panic!("max number of net components is {}", NET_COMPONENT_MAX);
-        }
-
-        let index = self.next_index;
-        self.next_index += 1;
-
         let type_id = TypeId::of::<C>();
         if self.index.contains_key(&type_id) {
             panic!("component already registered");
         }
-        self.index.insert(type_id, self.next_index);
+        let index = self.indices.register(name);
+        self.index.insert(type_id, index);
+        self.schemas.push(C::net_schema());
 
         self.packers.insert(
             index,
-            Box::new(|world, entity_set, store_fn| {
+            Box::new(|world, entity_set, pack_fn| {
                 let networked = world.read_storage::<Networked>();
                 let cs = world.read_storage::<C>();
-                (&networked, &cs)
-                    .join()
-                    .filter_map(|(Networked { entity_id, .. }, c)| {
-                        // include entities in the set, default to true if there's no set
-                        let include = entity_set.map(|s| s.contains(entity_id)).unwrap_or(true);
-                        if include {
-                            Some((*entity_id, store_fn(c)))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect()
+                for (&Networked { entity_id, .. }, c) in (&networked, &cs).join() {
+                    // include entities in the set, default to true if there's no set
+                    let include = entity_set.map(|s| s.contains(&entity_id)).unwrap_or(true);
+                    if include {
+                        pack_fn(entity_id, c)?;
+                    }
+                }
+                Ok(())
             }),
         );
 
         self.loaders.insert(
             index,
-            Box::new(|world, pack, load_fn| {
-                let networked = world.read_storage::<Networked>();
+            Box::new(|world, targets, load_fn| {
                 let mut cs = world.write_storage::<C>();
-                for (networked, c) in (&networked, &mut cs).join() {
-                    if let Some(store) = pack.get(&networked.entity_id) {
-                        load_fn(c, store);
+                for &(entity_id, e) in targets {
+                    if let Some(c) = cs.get_mut(e) {
+                        load_fn(entity_id, c)?;
                     }
                 }
+                Ok(())
             }),
         );
+
+        self.skippers.insert(index, C::skip_delta);
+    }
+
+    /// Names of the registered components, in the order of their indices.
+    pub fn manifest(&self) -> Vec<String> {
+        self.indices.manifest()
+    }
+
+    /// Names of the registered components, each with its schema.
+    pub fn schemas(&self) -> Vec<(&str, &str)> {
+        self.indices
+            .names()
+            .iter()
+            .cloned()
+            .zip(self.schemas.iter().map(|schema| schema.as_str()))
+            .collect()
+    }
+
+    /// Maps the component indices of received state from the peer's, as given by its manifest,
+    /// to ours.
+    pub fn set_remote_manifest(&mut self, manifest: &[String]) -> Result<(), Error> {
+        self.indices.set_remote_manifest(manifest)
     }
 
     pub fn net_store(
         &self,
         world: &World,
         entity_set: Option<&HashSet<EntityId>>,
-    ) -> ComponentStore {
+    ) -> Result<ComponentStore, Error> {
         let mut pack = HashMap::new();
-        let store_fn = |c: &NetComponent| c.net_store();
         for (component_index, packer) in self.packers.iter() {
-            pack.insert(*component_index, packer(world, entity_set, &store_fn));
+            let mut store = HashMap::new();
+            packer(world, entity_set, &mut |entity_id, c| {
+                store.insert(entity_id, c.net_store()?);
+                Ok(())
+            })?;
+            pack.insert(*component_index, store);
         }
-        ComponentStore(pack)
+        Ok(ComponentStore(pack))
     }
 
-    pub fn net_load(&self, world: &World, pack: ComponentStore) {
-        let load_fn = |c: &mut NetComponent, data: &[u8]| c.net_load(data);
-        for (component_index, store) in pack.0 {
-            let loader = self
-                .loaders
-                .get(&component_index)
-                .expect("attempt to load unregistered net component");
-            loader(world, store, &load_fn);
+    /// Loads full component state into the entities mapped from their net ids. Returns the ids
+    /// that had no local entity to load into.
+    pub fn net_load(
+        &self,
+        world: &World,
+        net_entities: &HashMap<EntityId, Entity>,
+        pack: ComponentStore,
+    ) -> Result<HashSet<EntityId>, Error> {
+        let pack = self.to_local(pack.0)?;
+        let mut unknown = HashSet::new();
+        for (component_index, loader) in self.loaders.iter() {
+            if let Some(store) = pack.get(component_index) {
+                let mut targets = Vec::new();
+                for entity_id in store.keys() {
+                    match net_entities.get(entity_id) {
+                        Some(&e) => targets.push((*entity_id, e)),
+                        None => {
+                            unknown.insert(*entity_id);
+                        }
+                    }
+                }
+
+                loader(world, &targets, &mut |entity_id, c| {
+                    c.net_load(&store[&entity_id])
+                })?;
+            }
         }
+        Ok(unknown)
     }
 
+    /// Packs the components whose state differs from the given baseline into a delta, each
+    /// holding only the fields that changed. Components that are unchanged since the baseline
+    /// are left out entirely, without a baseline every component is included.
     pub fn read_delta(
         &self,
         world: &World,
         entity_set: Option<&HashSet<EntityId>>,
-    ) -> ComponentDelta {
+        baseline: Option<&ComponentStore>,
+    ) -> Result<ComponentDelta, Error> {
         let mut pack = HashMap::new();
-        let store_fn = |c: &NetComponent| c.read_delta();
         for (component_index, packer) in self.packers.iter() {
-            pack.insert(*component_index, packer(world, entity_set, &store_fn));
+            let mut delta = HashMap::new();
+            packer(world, entity_set, &mut |entity_id, c| {
+                let state = c.net_store()?;
+                let baseline_state = baseline.and_then(|b| b.get(*component_index, entity_id));
+                if baseline_state != Some(state.as_slice()) {
+                    delta.insert(entity_id, c.read_delta(baseline_state)?);
+                }
+                Ok(())
+            })?;
+            if !delta.is_empty() {
+                pack.insert(*component_index, delta);
+            }
+        }
+        Ok(ComponentDelta(pack))
+    }
+
+    /// Applies a delta on top of the given baseline and returns the resulting snapshot, so it
+    /// can be used as the baseline for later deltas, along with the ids in the delta that had no
+    /// local entity to apply to.
+    pub fn write_delta(
+        &self,
+        world: &World,
+        net_entities: &HashMap<EntityId, Entity>,
+        baseline: Option<&ComponentStore>,
+        pack: ComponentDelta,
+    ) -> Result<(ComponentStore, HashSet<EntityId>), Error> {
+        let pack = pack.0;
+        let mut unknown = HashSet::new();
+        let mut snapshot = HashMap::new();
+        for (component_index, loader) in self.loaders.iter() {
+            let baseline = baseline.and_then(|b| b.0.get(component_index));
+            let delta = pack.get(component_index);
+
+            let mut targets = Vec::new();
+            if let Some(baseline) = baseline {
+                // entities destroyed since the baseline was taken are expected to be missing
+                for entity_id in baseline.keys() {
+                    if let Some(&e) = net_entities.get(entity_id) {
+                        targets.push((*entity_id, e));
+                    }
+                }
+            }
+            if let Some(delta) = delta {
+                for entity_id in delta.keys() {
+                    let in_baseline = baseline.map(|b| b.contains_key(entity_id)).unwrap_or(false);
+                    match net_entities.get(entity_id) {
+                        Some(&e) if !in_baseline => targets.push((*entity_id, e)),
+                        Some(_) => {}
+                        None => {
+                            unknown.insert(*entity_id);
+                        }
+                    }
+                }
+            }
+
+            let mut store = HashMap::new();
+            loader(world, &targets, &mut |entity_id, c| {
+                if let Some(data) = baseline.and_then(|b| b.get(&entity_id)) {
+                    c.net_load(data)?;
+                }
+                if let Some(data) = delta.and_then(|d| d.get(&entity_id)) {
+                    c.write_delta(data.as_bytes())?;
+                }
+                store.insert(entity_id, c.net_store()?);
+                Ok(())
+            })?;
+            snapshot.insert(*component_index, store);
+        }
+        Ok((ComponentStore(snapshot), unknown))
+    }
+
+    /// Approximate size in bytes of each entity in the delta once encoded, on top of the
+    /// `DELTA_HEADER_SIZE` the whole delta starts with.
+    pub fn entity_sizes(&self, pack: &ComponentDelta) -> HashMap<EntityId, usize> {
+        // every entity starts with its id and a bit per registered component telling which follow
+        let header_bits = 32 + self.indices.len();
+        let mut bits = HashMap::new();
+        for store in pack.0.values() {
+            for (entity_id, data) in store.iter() {
+                *bits.entry(*entity_id).or_insert(header_bits) += data.len();
+            }
         }
-        ComponentDelta(pack)
+        bits.into_iter()
+            .map(|(entity_id, bits)| (entity_id, (bits + 7) / 8))
+            .collect()
     }
 
-    pub fn write_delta(&self, world: &World, pack: ComponentDelta) {
-        let load_fn = |c: &mut NetComponent, data: &[u8]| c.write_delta(data);
-        for (component_index, delta) in pack.0 {
-            let loader = self
-                .loaders
-                .get(&component_index)
-                .expect("attempt to load unregistered net component");
-            loader(world, delta, &load_fn);
+    /// Packs a delta into a single bit stream: the number of components the mask of each entity
+    /// has a bit for and the number of entities, followed by each entity's id, its mask and the
+    /// deltas of the components set in it, back to back.
+    pub fn encode_delta(&self, pack: &ComponentDelta) -> Vec<u8> {
+        let mut entity_ids: Vec<EntityId> = pack
+            .0
+            .values()
+            .flat_map(|store| store.keys().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        entity_ids.sort_by_key(|entity_id| (entity_id.index, entity_id.generation));
+
+        let mut writer = BitWriter::new();
+        writer.write_bits(self.indices.len() as u32, 8);
+        writer.write_bits(entity_ids.len() as u32, 16);
+        for entity_id in entity_ids {
+            entity_id.write(&mut writer);
+            let deltas: Vec<Option<&BitWriter>> = (0..self.indices.len())
+                .map(|index| {
+                    pack.0
+                        .get(&(index as NetComponentIndex))
+                        .and_then(|store| store.get(&entity_id))
+                })
+                .collect();
+            for delta in deltas.iter() {
+                writer.write_bool(delta.is_some());
+            }
+            for delta in deltas.into_iter().flatten() {
+                writer.append(delta);
+            }
         }
+        writer.into_bytes()
+    }
+
+    /// Unpacks a delta encoded by the peer's `encode_delta`, rekeyed to our component indices.
+    pub fn decode_delta(&self, data: &[u8]) -> Result<ComponentDelta, Error> {
+        let mut reader = BitReader::new(data);
+        let component_count = reader.read_bits(8)?;
+        let entity_count = reader.read_bits(16)?;
+
+        let mut pack = HashMap::new();
+        for _ in 0..entity_count {
+            let entity_id = EntityId::read(&mut reader)?;
+            let mut indices = Vec::new();
+            for remote_index in 0..component_count {
+                if reader.read_bool()? {
+                    indices.push(self.local_index(remote_index as NetComponentIndex)?);
+                }
+            }
+            for index in indices {
+                // the stream has no lengths, the component's encoding tells where it ends
+                let mut end = reader.clone();
+                self.skippers[&index](&mut end)?;
+                let bits = end.position() - reader.position();
+                let mut delta = BitWriter::new();
+                delta.copy_from(&mut reader, bits)?;
+
+                let store = pack.entry(index).or_insert_with(HashMap::new);
+                if store.insert(entity_id, delta).is_some() {
+                    return Err(ProtocolError::Malformed(format!(
+                        "entity {:?} appears more than once in delta",
+                        entity_id
+                    )).into());
+                }
+            }
+        }
+        Ok(ComponentDelta(pack))
+    }
+
+    /// Rekeys received component state from the peer's indices to ours.
+    fn to_local<T>(
+        &self,
+        pack: HashMap<NetComponentIndex, T>,
+    ) -> Result<HashMap<NetComponentIndex, T>, Error> {
+        pack.into_iter()
+            .map(|(remote_index, data)| Ok((self.local_index(remote_index)?, data)))
+            .collect()
+    }
+
+    fn local_index(&self, remote_index: NetComponentIndex) -> Result<NetComponentIndex, Error> {
+        self.indices
+            .local_index(remote_index)
+            .ok_or_else(|| ProtocolError::UnknownComponent(remote_index).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use components::Transform;
+    use specs::VecStorage;
+
+    #[derive(NetComponent)]
+    struct Health(u8);
+
+    impl Component for Health {
+        type Storage = VecStorage<Self>;
+    }
+
+    fn transform_delta(entities: &[(EntityId, Transform)]) -> ComponentDelta {
+        let store = entities
+            .iter()
+            .map(|&(entity_id, ref transform)| (entity_id, transform.read_delta(None).unwrap()))
+            .collect();
+        ComponentDelta(vec![(0, store)].into_iter().collect())
+    }
+
+    fn transform_adapter() -> NetComponentAdapter {
+        let mut adapter = NetComponentAdapter::new();
+        adapter.register_component::<Transform>("transform");
+        adapter
+    }
+
+    fn entity_id(index: u16) -> EntityId {
+        EntityId {
+            index,
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn allocator_reuses_freed_indices_with_a_new_generation() {
+        let mut allocator = EntityIdAllocator::new();
+        let a = allocator.allocate().unwrap();
+        let b = allocator.allocate().unwrap();
+        assert_ne!(a.index, b.index);
+
+        allocator.free(a);
+        let c = allocator.allocate().unwrap();
+        assert_eq!(c.index, a.index);
+        assert_ne!(c.generation, a.generation);
+        assert_ne!(c, a);
+    }
+
+    #[test]
+    fn allocator_ignores_freeing_stale_ids() {
+        let mut allocator = EntityIdAllocator::new();
+        let a = allocator.allocate().unwrap();
+        allocator.free(a);
+        allocator.free(a);
+
+        let b = allocator.allocate().unwrap();
+        let c = allocator.allocate().unwrap();
+        assert_eq!(b.index, a.index);
+        assert_ne!(c.index, a.index);
+    }
+
+    #[test]
+    fn allocator_reuses_oldest_freed_index_first() {
+        let mut allocator = EntityIdAllocator::new();
+        let ids: Vec<EntityId> = (0..3).map(|_| allocator.allocate().unwrap()).collect();
+        allocator.free(ids[2]);
+        allocator.free(ids[0]);
+
+        assert_eq!(allocator.allocate().unwrap().index, ids[2].index);
+        assert_eq!(allocator.allocate().unwrap().index, ids[0].index);
+    }
+
+    #[test]
+    fn encoded_delta_round_trips() {
+        let moved = Transform {
+            position: Vec2::new(100.0, -50.0),
+            rotation: 1.0,
+            ..Transform::default()
+        };
+        let delta = transform_delta(&[(entity_id(3), Transform::default()), (entity_id(7), moved)]);
+
+        let adapter = transform_adapter();
+        let encoded = adapter.encode_delta(&delta);
+        // 24 bits of component and entity count, then per entity 81 bits: its id, a mask bit, a
+        // changed flag per field and the fields, without any length framing
+        assert_eq!(encoded.len(), 24);
+
+        let decoded = adapter.decode_delta(&encoded).unwrap();
+        assert_eq!(decoded.0.len(), 1);
+        assert_eq!(decoded.0[&0].len(), 2);
+        for (entity_id, data) in delta.0[&0].iter() {
+            assert!(decoded.0[&0][entity_id] == *data);
+        }
+    }
+
+    #[test]
+    fn decoding_unknown_components_is_an_error() {
+        let mut adapter = transform_adapter();
+        let delta = transform_delta(&[(entity_id(0), Transform::default())]);
+        let encoded = adapter.encode_delta(&delta);
+        // the peer has no components
+        adapter.set_remote_manifest(&[]).unwrap();
+        assert!(adapter.decode_delta(&encoded).is_err());
+    }
+
+    #[test]
+    fn decoding_truncated_deltas_is_an_error() {
+        let adapter = transform_adapter();
+        let delta = transform_delta(&[(entity_id(0), Transform::default())]);
+        let encoded = adapter.encode_delta(&delta);
+        assert!(adapter.decode_delta(&encoded[..encoded.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn indices_are_mapped_between_peers_by_name() {
+        let mut ours = NamedIndices::new("thing");
+        ours.register("a");
+        ours.register("b");
+        ours.register("c");
+        assert_eq!(ours.local_index(1), Some(1));
+
+        ours.set_remote_manifest(&["c".to_string(), "a".to_string()]).unwrap();
+        assert_eq!(ours.local_index(0), Some(2));
+        assert_eq!(ours.local_index(1), Some(0));
+        assert_eq!(ours.local_index(2), None);
+
+        assert!(ours.set_remote_manifest(&["d".to_string()]).is_err());
+    }
+
+    #[test]
+    fn deltas_decode_when_peers_register_components_in_another_order() {
+        let mut server = NetComponentAdapter::new();
+        server.register_component::<Health>("health");
+        server.register_component::<Transform>("transform");
+        let mut client = NetComponentAdapter::new();
+        client.register_component::<Transform>("transform");
+        client.register_component::<Health>("health");
+        client.set_remote_manifest(&server.manifest()).unwrap();
+
+        let transform = Transform::default().read_delta(None).unwrap();
+        let health = Health(30).read_delta(None).unwrap();
+        let mut delta = HashMap::new();
+        delta.insert(0, vec![(entity_id(1), health.clone())].into_iter().collect());
+        delta.insert(1, vec![(entity_id(1), transform.clone())].into_iter().collect());
+
+        let decoded = client
+            .decode_delta(&server.encode_delta(&ComponentDelta(delta)))
+            .unwrap();
+        assert!(decoded.0[&0][&entity_id(1)] == transform);
+        assert!(decoded.0[&1][&entity_id(1)] == health);
     }
 }
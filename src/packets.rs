@@ -1,17 +1,123 @@
-use net::{ComponentDelta, ComponentStore, EntityId};
+use bincode;
+use failure::Error;
+
+use channel::Channel;
+use net::{ComponentStore, EntityId, NetComponentAdapter, ProtocolError, SnapshotSequence};
+use prefab;
 use prefab::PrefabIndex;
 
+/// Bumped whenever packets change in a way older peers can't decode.
+pub static PROTOCOL_VERSION: u32 = 1;
+
+pub type InputSequence = u32;
+/// Number of the server simulation step, the shared clock between server and clients.
+pub type Tick = u32;
+
 #[derive(Serialize, Deserialize)]
 pub struct EntitiesStore {
     pub entities: Vec<(EntityId, PrefabIndex)>,
     pub components: ComponentStore,
 }
 
+/// Names of the server's net components and prefabs in the order of their indices, so clients
+/// built with a different registration order can map them to their own.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub components: Vec<String>,
+    pub prefabs: Vec<String>,
+}
+
+/// Checksum of the registered net components, including how they're encoded, and prefabs.
+/// Registration order doesn't count, indices are mapped between peers by name.
+pub fn registry_checksum(net_adapter: &NetComponentAdapter, prefabs: &prefab::Registry) -> u64 {
+    let mut entries: Vec<String> = net_adapter
+        .schemas()
+        .into_iter()
+        .map(|(name, schema)| format!("component {} {{ {} }}", name, schema))
+        .chain(
+            prefabs
+                .manifest()
+                .into_iter()
+                .map(|name| format!("prefab {}", name)),
+        )
+        .collect();
+    entries.sort();
+
+    // FNV-1a, the std hashers aren't guaranteed to be the same between builds
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for entry in entries {
+        for byte in entry.bytes().chain(Some(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Packet {
-    Connect,
-    Initialize,
+    Connect {
+        protocol_version: u32,
+        checksum: u64,
+        player_name: String,
+    },
+    ConnectRejected {
+        reason: String,
+    },
+    Initialize {
+        player_entity: EntityId,
+        manifest: Manifest,
+    },
     CreateEntities(EntitiesStore),
-    Update(ComponentDelta),
-    PlayerInput { left: bool, right: bool, up: bool },
+    DestroyEntities(Vec<EntityId>),
+    Update {
+        sequence: SnapshotSequence,
+        // server tick the snapshot was taken at
+        tick: Tick,
+        baseline: Option<SnapshotSequence>,
+        // last player input the server has applied, for the client to reconcile its prediction
+        last_input: Option<InputSequence>,
+        // bit packed with `NetComponentAdapter::encode_delta`
+        delta: Vec<u8>,
+    },
+    SnapshotAck(SnapshotSequence),
+    PlayerInput {
+        sequence: InputSequence,
+        left: bool,
+        right: bool,
+        up: bool,
+        // server tick other entities were drawn at, to resolve hits against what the player saw
+        view_tick: Option<Tick>,
+    },
+    // the client's local time, echoed back in the pong to measure the round trip
+    Ping { time: f64 },
+    Pong { time: f64, tick: Tick },
+}
+
+impl Packet {
+    /// Channel the packet is sent on. Connection setup and entity lifetimes must arrive exactly
+    /// once and in order, state updates are superseded by the next one anyway.
+    pub fn channel(&self) -> Channel {
+        match *self {
+            Packet::Connect { .. }
+            | Packet::ConnectRejected { .. }
+            | Packet::Initialize { .. }
+            | Packet::CreateEntities(_)
+            | Packet::DestroyEntities(_) => Channel::Reliable,
+            Packet::Update { .. }
+            | Packet::SnapshotAck(_)
+            | Packet::PlayerInput { .. }
+            | Packet::Ping { .. }
+            | Packet::Pong { .. } => Channel::Unreliable,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Fails with a `ProtocolError` for anything that isn't a packet, never panics.
+    pub fn decode(data: &[u8]) -> Result<Packet, Error> {
+        bincode::deserialize(data).map_err(|e| ProtocolError::Malformed(e.to_string()).into())
+    }
 }
@@ -9,8 +9,14 @@ pub use self::registry::{PrefabIndex, Registry};
 
 pub trait Prefab {
     fn create(world: &mut World) -> Result<Entity, Error>;
+
+    /// How important keeping clients up to date on entities of this prefab is compared to
+    /// others, when there isn't enough bandwidth to send everything.
+    fn net_priority() -> f32 {
+        1.0
+    }
 }
 
 pub fn register_prefabs(registry: &mut Registry) {
-    registry.register_prefab::<PlayerPrefab>();
+    registry.register_prefab::<PlayerPrefab>("player");
 }
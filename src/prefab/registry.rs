@@ -5,32 +5,35 @@ use std::any::TypeId;
 
 use specs::{Entity, World};
 
+use net::{NamedIndices, ProtocolError};
 use prefab::Prefab;
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct PrefabIndex(u8);
-static MAX_PREFAB_TYPE: usize = std::u8::MAX as usize;
 
 pub struct Registry {
     prefabs: HashMap<TypeId, u8>,
+    indices: NamedIndices,
     loaders: Vec<Box<Fn(&mut World) -> Result<Entity, Error>>>,
+    net_priorities: Vec<f32>,
 }
 
 impl Registry {
     pub fn new() -> Registry {
         Registry {
             prefabs: HashMap::new(),
+            indices: NamedIndices::new("prefab"),
             loaders: Vec::new(),
+            net_priorities: Vec::new(),
         }
     }
 
-    pub fn register_prefab<T: Prefab + 'static>(&mut self) {
-        if self.loaders.len() > MAX_PREFAB_TYPE {
-            panic!("max number of prefabs is {}", MAX_PREFAB_TYPE);
-        }
-        self.prefabs
-            .insert(TypeId::of::<T>(), self.loaders.len() as u8);
+    /// Registers a prefab under its name, see `NamedIndices`.
+    pub fn register_prefab<T: Prefab + 'static>(&mut self, name: &'static str) {
+        let index = self.indices.register(name);
+        self.prefabs.insert(TypeId::of::<T>(), index);
         self.loaders.push(Box::new(T::create));
+        self.net_priorities.push(T::net_priority());
     }
 
     pub fn create<T: Prefab + 'static>(
@@ -45,11 +48,36 @@ impl Registry {
         Ok((e, PrefabIndex(prefab)))
     }
 
+    /// Names of the registered prefabs, in the order of their indices.
+    pub fn manifest(&self) -> Vec<String> {
+        self.indices.manifest()
+    }
+
+    /// Maps prefab indices from the peer's, as given by its manifest, to ours.
+    pub fn set_remote_manifest(&mut self, manifest: &[String]) -> Result<(), Error> {
+        self.indices.set_remote_manifest(manifest)
+    }
+
+    /// Our index of a prefab index received from the peer.
+    pub fn local_index(&self, prefab: PrefabIndex) -> Result<PrefabIndex, Error> {
+        self.indices
+            .local_index(prefab.0)
+            .map(PrefabIndex)
+            .ok_or_else(|| ProtocolError::UnknownPrefab(prefab.0).into())
+    }
+
     pub fn instantiate(&self, world: &mut World, prefab: PrefabIndex) -> Result<Entity, Error> {
         let loader = self
             .loaders
             .get(prefab.0 as usize)
-            .ok_or_else(|| format_err!("attempt to load unregistered prefab"))?;
+            .ok_or_else(|| ProtocolError::UnknownPrefab(prefab.0))?;
         loader(world)
     }
+
+    pub fn net_priority(&self, prefab: PrefabIndex) -> f32 {
+        self.net_priorities
+            .get(prefab.0 as usize)
+            .cloned()
+            .unwrap_or(1.0)
+    }
 }
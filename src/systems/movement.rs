@@ -1,6 +1,6 @@
 use specs::{Join, ReadStorage, System, WriteStorage};
 
-use components::{Transform, Velocity};
+use components::{Transform, Velocity, WORLD_BOUND};
 use game_server::TIMESTEP;
 
 pub struct MovementSystem {}
@@ -16,6 +16,42 @@ impl<'a> System<'a> for MovementSystem {
     fn run(&mut self, (mut transform, vel): Self::SystemData) {
         for (transform, velocity) in (&mut transform, &vel).join() {
             transform.position += velocity.0 * TIMESTEP as f32;
+            // the world ends at its bounds, further out couldn't be sent to clients
+            transform.position.x = transform.position.x.max(-WORLD_BOUND).min(WORLD_BOUND);
+            transform.position.y = transform.position.y.max(-WORLD_BOUND).min(WORLD_BOUND);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embla::math::Vec2;
+    use specs::{Builder, RunNow, World};
+
+    #[test]
+    fn keeps_entities_within_the_world_bounds() {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<Velocity>();
+        let e = world
+            .create_entity()
+            .with(Transform {
+                position: Vec2::new(WORLD_BOUND - 1.0, -WORLD_BOUND + 1.0),
+                scale: 1.0,
+                rotation: 0.0,
+            })
+            .with(Velocity(Vec2::new(300.0, -300.0)))
+            .build();
+
+        let mut system = MovementSystem::new();
+        for _ in 0..10 {
+            system.run_now(&world.res);
+        }
+
+        let transforms = world.read_storage::<Transform>();
+        let position = transforms.get(e).unwrap().position;
+        assert_eq!(position.x, WORLD_BOUND);
+        assert_eq!(position.y, -WORLD_BOUND);
+    }
+}
@@ -0,0 +1,137 @@
+use failure::Error;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use net::ClientId;
+use transport::{Transport, TransportEvent, SERVER_CONNECTION};
+
+struct Hub {
+    next_client_id: ClientId,
+    connected: HashSet<ClientId>,
+    server_events: VecDeque<TransportEvent>,
+    client_events: HashMap<ClientId, VecDeque<TransportEvent>>,
+}
+
+impl Hub {
+    fn disconnect_client(&mut self, client_id: ClientId) {
+        if self.connected.remove(&client_id) {
+            self.server_events
+                .push_back(TransportEvent::Disconnected(client_id));
+        }
+    }
+}
+
+/// Server side of a transport that passes packets between a server and clients living in the
+/// same process.
+pub struct MemoryServerTransport {
+    hub: Rc<RefCell<Hub>>,
+}
+
+impl MemoryServerTransport {
+    pub fn new() -> MemoryServerTransport {
+        MemoryServerTransport {
+            hub: Rc::new(RefCell::new(Hub {
+                next_client_id: 0,
+                connected: HashSet::new(),
+                server_events: VecDeque::new(),
+                client_events: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn connect(&self) -> Result<MemoryClientTransport, Error> {
+        let mut hub = self.hub.borrow_mut();
+
+        let client_id = hub.next_client_id;
+        hub.next_client_id = client_id
+            .checked_add(1)
+            .ok_or_else(|| format_err!("ran out of client ids"))?;
+
+        let mut client_events = VecDeque::new();
+        client_events.push_back(TransportEvent::Connected(SERVER_CONNECTION));
+        hub.client_events.insert(client_id, client_events);
+        hub.connected.insert(client_id);
+        hub.server_events
+            .push_back(TransportEvent::Connected(client_id));
+
+        Ok(MemoryClientTransport {
+            hub: self.hub.clone(),
+            client_id,
+        })
+    }
+}
+
+impl Transport for MemoryServerTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        let mut hub = self.hub.borrow_mut();
+        if !hub.connected.contains(&connection) {
+            return Err(format_err!("attempt to send to unknown client {}", connection));
+        }
+        if let Some(events) = hub.client_events.get_mut(&connection) {
+            events.push_back(TransportEvent::Received(SERVER_CONNECTION, data));
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        Ok(self.hub.borrow_mut().server_events.drain(..).collect())
+    }
+
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error> {
+        let mut hub = self.hub.borrow_mut();
+        if hub.connected.remove(&connection) {
+            if let Some(events) = hub.client_events.get_mut(&connection) {
+                events.push_back(TransportEvent::Disconnected(SERVER_CONNECTION));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MemoryClientTransport {
+    hub: Rc<RefCell<Hub>>,
+    client_id: ClientId,
+}
+
+impl Transport for MemoryClientTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        if connection != SERVER_CONNECTION {
+            return Err(format_err!("client can only send to the server"));
+        }
+
+        let mut hub = self.hub.borrow_mut();
+        if !hub.connected.contains(&self.client_id) {
+            return Err(format_err!("not connected"));
+        }
+        hub.server_events
+            .push_back(TransportEvent::Received(self.client_id, data));
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        let mut hub = self.hub.borrow_mut();
+        Ok(hub
+            .client_events
+            .get_mut(&self.client_id)
+            .map(|events| events.drain(..).collect())
+            .unwrap_or_else(Vec::new))
+    }
+
+    fn disconnect(&mut self, _: ClientId) -> Result<(), Error> {
+        self.hub.borrow_mut().disconnect_client(self.client_id);
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryClientTransport {
+    fn drop(&mut self) {
+        let mut hub = self.hub.borrow_mut();
+        hub.disconnect_client(self.client_id);
+        hub.client_events.remove(&self.client_id);
+    }
+}
@@ -0,0 +1,134 @@
+mod memory;
+mod simulator;
+#[cfg(not(target_arch = "wasm32"))]
+mod udp;
+#[cfg(not(target_arch = "wasm32"))]
+mod websocket;
+#[cfg(target_arch = "wasm32")]
+mod websocket_web;
+
+use failure::Error;
+
+use net::ClientId;
+
+pub use self::memory::*;
+pub use self::simulator::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::udp::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::websocket::*;
+#[cfg(target_arch = "wasm32")]
+pub use self::websocket_web::*;
+
+/// Connection id a client side transport reports for its connection to the server.
+pub static SERVER_CONNECTION: ClientId = 0;
+
+pub enum TransportEvent {
+    Connected(ClientId),
+    Disconnected(ClientId),
+    Received(ClientId, Vec<u8>),
+}
+
+/// Moves encoded packets between the game and its peers. A server side transport has one
+/// connection per client, a client side transport a single connection to the server.
+pub trait Transport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error>;
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error>;
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error>;
+
+    /// Advances the transport's notion of time, called once per update before polling.
+    fn tick(&mut self, _dt: f64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        (**self).send(connection, data)
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        (**self).poll()
+    }
+
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error> {
+        (**self).disconnect(connection)
+    }
+
+    fn tick(&mut self, dt: f64) -> Result<(), Error> {
+        (**self).tick(dt)
+    }
+}
+
+/// Polls a server and a client transport until the events either has received so far satisfy
+/// `done`, panicking if that takes more than a couple of seconds. The events are returned.
+#[cfg(all(test, not(target_arch = "wasm32")))]
+pub fn poll_until<S, C, F>(
+    server: &mut S,
+    client: &mut C,
+    mut done: F,
+) -> (Vec<TransportEvent>, Vec<TransportEvent>)
+where
+    S: Transport,
+    C: Transport,
+    F: FnMut(&[TransportEvent], &[TransportEvent]) -> bool,
+{
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let mut server_events = Vec::new();
+    let mut client_events = Vec::new();
+    while !done(&server_events, &client_events) {
+        assert!(start.elapsed() < Duration::from_secs(2), "timed out polling transports");
+        server_events.extend(server.poll().unwrap());
+        client_events.extend(client.poll().unwrap());
+        thread::sleep(Duration::from_millis(1));
+    }
+    (server_events, client_events)
+}
+
+/// Checks that a pair of transports exchanges data and reports disconnects from either end the
+/// way the game relies on. `connect` returns a newly connected server and client transport, and
+/// the id the server knows the client by.
+#[cfg(all(test, not(target_arch = "wasm32")))]
+pub fn check_conformance<S, C, F>(connect: F)
+where
+    S: Transport,
+    C: Transport,
+    F: Fn() -> (S, C, ClientId),
+{
+    let (mut server, mut client, client_id) = connect();
+    client.send(SERVER_CONNECTION, vec![1, 2, 3]).unwrap();
+    server.send(client_id, vec![4, 5]).unwrap();
+    let (server_events, client_events) = poll_until(&mut server, &mut client, |s, c| {
+        !s.is_empty() && !c.is_empty()
+    });
+    match server_events[..] {
+        [TransportEvent::Received(id, ref data)] if id == client_id => assert_eq!(data, &[1, 2, 3]),
+        _ => panic!("expected the server to receive the client's data"),
+    }
+    match client_events[..] {
+        [TransportEvent::Received(id, ref data)] if id == SERVER_CONNECTION => {
+            assert_eq!(data, &[4, 5])
+        }
+        _ => panic!("expected the client to receive the server's data"),
+    }
+
+    let (mut server, mut client, client_id) = connect();
+    client.disconnect(SERVER_CONNECTION).unwrap();
+    let (server_events, _) = poll_until(&mut server, &mut client, |s, _| !s.is_empty());
+    match server_events[..] {
+        [TransportEvent::Disconnected(id)] if id == client_id => {}
+        _ => panic!("expected the server to see the client disconnect"),
+    }
+
+    let (mut server, mut client, client_id) = connect();
+    server.disconnect(client_id).unwrap();
+    let (_, client_events) = poll_until(&mut server, &mut client, |_, c| !c.is_empty());
+    match client_events[..] {
+        [TransportEvent::Disconnected(id)] if id == SERVER_CONNECTION => {}
+        _ => panic!("expected the client to see the server disconnect it"),
+    }
+    assert!(server.send(client_id, vec![1]).is_err());
+}
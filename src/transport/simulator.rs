@@ -0,0 +1,384 @@
+use failure::Error;
+
+use net::ClientId;
+use transport::{Transport, TransportEvent};
+
+/// Conditions applied to packets travelling in one direction.
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConditions {
+    /// Base one way delay in seconds.
+    pub latency: f64,
+    /// Maximum random deviation from the base delay in seconds.
+    pub jitter: f64,
+    /// Probability of a packet being dropped.
+    pub loss: f64,
+    /// Probability of a packet arriving twice.
+    pub duplicate: f64,
+    /// Probability of a packet being held back long enough to arrive after later packets.
+    pub reorder: f64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            latency: 0.0,
+            jitter: 0.0,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimulatorConfig {
+    pub seed: u64,
+    /// Packets sent through the wrapped transport.
+    pub outgoing: LinkConditions,
+    /// Packets received through the wrapped transport.
+    pub incoming: LinkConditions,
+}
+
+impl SimulatorConfig {
+    /// Parses a comma separated list of `key=value` settings, such as
+    /// `latency=100,jitter=20,loss=0.05,seed=3`. Keys are `latency` and `jitter` in milliseconds
+    /// and `loss`, `duplicate` and `reorder` as probabilities, applying to both directions unless
+    /// prefixed with `out.` or `in.`.
+    pub fn parse(spec: &str) -> Result<SimulatorConfig, Error> {
+        let mut config = SimulatorConfig {
+            seed: 0,
+            outgoing: LinkConditions::default(),
+            incoming: LinkConditions::default(),
+        };
+
+        for setting in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts
+                .next()
+                .ok_or_else(|| format_err!("missing value for network simulator setting {}", key))?;
+
+            if key == "seed" {
+                config.seed = value.parse()?;
+                continue;
+            }
+
+            let (directions, key) = if key.starts_with("out.") {
+                ((true, false), &key[4..])
+            } else if key.starts_with("in.") {
+                ((false, true), &key[3..])
+            } else {
+                ((true, true), key)
+            };
+            let value: f64 = value.parse()?;
+
+            let mut links = Vec::new();
+            if directions.0 {
+                links.push(&mut config.outgoing);
+            }
+            if directions.1 {
+                links.push(&mut config.incoming);
+            }
+            for link in links {
+                match key {
+                    "latency" => link.latency = value / 1000.0,
+                    "jitter" => link.jitter = value / 1000.0,
+                    "loss" => link.loss = value,
+                    "duplicate" => link.duplicate = value,
+                    "reorder" => link.reorder = value,
+                    _ => return Err(format_err!("unknown network simulator setting {}", key)),
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Small xorshift generator, so a given seed always reproduces the same network conditions.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // xorshift gets stuck on zero
+        let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        Rng(if state == 0 { 1 } else { state })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniformly distributed in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+struct InFlight<T> {
+    deliver_at: f64,
+    // breaks ties so packets due at the same time keep their order
+    order: u64,
+    item: T,
+}
+
+/// One direction of the simulated link.
+struct Link<T> {
+    conditions: LinkConditions,
+    in_flight: Vec<InFlight<T>>,
+    next_order: u64,
+    // latest delivery time of a packet that wasn't reordered, later packets never overtake it
+    last_delivery: f64,
+}
+
+impl<T: Clone> Link<T> {
+    fn new(conditions: LinkConditions) -> Link<T> {
+        Link {
+            conditions,
+            in_flight: Vec::new(),
+            next_order: 0,
+            last_delivery: 0.0,
+        }
+    }
+
+    fn push(&mut self, rng: &mut Rng, now: f64, item: T, lossy: bool) {
+        if lossy && rng.chance(self.conditions.loss) {
+            return;
+        }
+
+        let copies = if lossy && rng.chance(self.conditions.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let jitter = (rng.next_f64() * 2.0 - 1.0) * self.conditions.jitter;
+            let mut deliver_at = now + (self.conditions.latency + jitter).max(0.0);
+            if lossy && rng.chance(self.conditions.reorder) {
+                // hold the packet back by up to a full extra round of latency and jitter
+                deliver_at += rng.next_f64() * (self.conditions.latency + self.conditions.jitter)
+                    + 0.001;
+            } else {
+                deliver_at = deliver_at.max(self.last_delivery);
+                self.last_delivery = deliver_at;
+            }
+
+            self.in_flight.push(InFlight {
+                deliver_at,
+                order: self.next_order,
+                item: item.clone(),
+            });
+            self.next_order += 1;
+        }
+    }
+
+    fn take_due(&mut self, now: f64) -> Vec<T> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if self.in_flight[i].deliver_at <= now {
+                due.push(self.in_flight.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by(|a, b| {
+            a.deliver_at
+                .partial_cmp(&b.deliver_at)
+                .unwrap()
+                .then(a.order.cmp(&b.order))
+        });
+        due.into_iter().map(|in_flight| in_flight.item).collect()
+    }
+}
+
+#[derive(Clone)]
+enum Incoming {
+    Connected(ClientId),
+    Disconnected(ClientId),
+    Received(ClientId, Vec<u8>),
+}
+
+/// Wraps a transport and applies latency, jitter, loss, duplication and reordering to the
+/// packets going through it. Connect and disconnect events are delayed but never lost.
+pub struct SimulatedTransport<T: Transport> {
+    transport: T,
+    rng: Rng,
+    time: f64,
+    outgoing: Link<(ClientId, Vec<u8>)>,
+    incoming: Link<Incoming>,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(transport: T, config: SimulatorConfig) -> SimulatedTransport<T> {
+        SimulatedTransport {
+            transport,
+            rng: Rng::new(config.seed),
+            time: 0.0,
+            outgoing: Link::new(config.outgoing),
+            incoming: Link::new(config.incoming),
+        }
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        self.outgoing
+            .push(&mut self.rng, self.time, (connection, data), true);
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        for event in self.transport.poll()? {
+            let (incoming, lossy) = match event {
+                TransportEvent::Connected(c) => (Incoming::Connected(c), false),
+                TransportEvent::Disconnected(c) => (Incoming::Disconnected(c), false),
+                TransportEvent::Received(c, data) => (Incoming::Received(c, data), true),
+            };
+            self.incoming
+                .push(&mut self.rng, self.time, incoming, lossy);
+        }
+
+        Ok(self
+            .incoming
+            .take_due(self.time)
+            .into_iter()
+            .map(|incoming| match incoming {
+                Incoming::Connected(c) => TransportEvent::Connected(c),
+                Incoming::Disconnected(c) => TransportEvent::Disconnected(c),
+                Incoming::Received(c, data) => TransportEvent::Received(c, data),
+            })
+            .collect())
+    }
+
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error> {
+        self.transport.disconnect(connection)
+    }
+
+    fn tick(&mut self, dt: f64) -> Result<(), Error> {
+        self.time += dt;
+        self.transport.tick(dt)?;
+
+        for (connection, data) in self.outgoing.take_due(self.time) {
+            // the connection may have gone away while the packet was in flight
+            let _ = self.transport.send(connection, data);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::{MemoryServerTransport, SERVER_CONNECTION};
+
+    /// Sends numbered packets from a simulated client, one per 10 ms, and returns the numbers
+    /// the server received along with the time they arrived.
+    fn deliveries(config: SimulatorConfig, count: u8) -> Vec<(f64, u8)> {
+        let mut server = MemoryServerTransport::new();
+        let mut client = SimulatedTransport::new(server.connect().unwrap(), config);
+
+        let mut received = Vec::new();
+        let dt = 0.01;
+        for step in 0..200 {
+            if step < count as usize {
+                client.send(SERVER_CONNECTION, vec![step as u8]).unwrap();
+            }
+            client.tick(dt).unwrap();
+            for event in server.poll().unwrap() {
+                if let TransportEvent::Received(_, data) = event {
+                    received.push(((step + 1) as f64 * dt, data[0]));
+                }
+            }
+        }
+        received
+    }
+
+    fn config(conditions: LinkConditions, seed: u64) -> SimulatorConfig {
+        SimulatorConfig {
+            seed,
+            outgoing: conditions,
+            incoming: LinkConditions::default(),
+        }
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config =
+            SimulatorConfig::parse("latency=100,out.loss=0.5,in.jitter=20,seed=3").unwrap();
+        assert_eq!(config.seed, 3);
+        assert_eq!(config.outgoing.latency, 0.1);
+        assert_eq!(config.incoming.latency, 0.1);
+        assert_eq!(config.outgoing.loss, 0.5);
+        assert_eq!(config.incoming.loss, 0.0);
+        assert_eq!(config.incoming.jitter, 0.02);
+        assert_eq!(config.outgoing.jitter, 0.0);
+
+        assert!(SimulatorConfig::parse("bandwidth=10").is_err());
+        assert!(SimulatorConfig::parse("latency").is_err());
+    }
+
+    #[test]
+    fn delays_packets_by_the_latency() {
+        let conditions = LinkConditions {
+            latency: 0.1,
+            ..LinkConditions::default()
+        };
+        let received = deliveries(config(conditions, 0), 10);
+        assert_eq!(received.len(), 10);
+        for (i, &(time, number)) in received.iter().enumerate() {
+            assert_eq!(number, i as u8);
+            let sent = i as f64 * 0.01;
+            assert!(time - sent >= 0.1 - 1e-9 && time - sent < 0.1 + 0.01 + 1e-9);
+        }
+    }
+
+    #[test]
+    fn jitter_without_reordering_keeps_packets_in_order() {
+        let conditions = LinkConditions {
+            latency: 0.05,
+            jitter: 0.04,
+            ..LinkConditions::default()
+        };
+        let numbers: Vec<u8> = deliveries(config(conditions, 7), 50)
+            .into_iter()
+            .map(|(_, number)| number)
+            .collect();
+        assert_eq!(numbers, (0..50).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn drops_everything_at_full_loss() {
+        let conditions = LinkConditions {
+            loss: 1.0,
+            ..LinkConditions::default()
+        };
+        assert!(deliveries(config(conditions, 0), 20).is_empty());
+    }
+
+    #[test]
+    fn same_seed_reproduces_the_same_conditions() {
+        let conditions = LinkConditions {
+            latency: 0.05,
+            jitter: 0.03,
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.2,
+        };
+        let a = deliveries(config(conditions, 42), 100);
+        let b = deliveries(config(conditions, 42), 100);
+        let c = deliveries(config(conditions, 43), 100);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.len() < 110);
+    }
+}
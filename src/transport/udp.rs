@@ -0,0 +1,350 @@
+use failure::Error;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use net::ClientId;
+use transport::{Transport, TransportEvent, SERVER_CONNECTION};
+
+// every datagram starts with one of these, followed by the encoded packet for data datagrams
+static DATAGRAM_CONNECT: u8 = 0;
+static DATAGRAM_DATA: u8 = 1;
+static DATAGRAM_DISCONNECT: u8 = 2;
+static DATAGRAM_HEARTBEAT: u8 = 3;
+
+static MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// a peer we haven't heard from in this long is considered disconnected
+static TIMEOUT_MS: u64 = 5000;
+// idle connections send heartbeats so they don't time out
+static HEARTBEAT_INTERVAL_MS: u64 = 1000;
+static CONNECT_RETRY_INTERVAL_MS: u64 = 250;
+
+fn datagram(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(data.len() + 1);
+    datagram.push(kind);
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+struct UdpClient {
+    address: SocketAddr,
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+/// Server side UDP transport. Clients are identified by their socket address, the socket is
+/// non-blocking and read from every time the transport is polled.
+pub struct UdpServerTransport {
+    socket: UdpSocket,
+    clients: HashMap<ClientId, UdpClient>,
+    addresses: HashMap<SocketAddr, ClientId>,
+    buffer: Vec<u8>,
+}
+
+impl UdpServerTransport {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<UdpServerTransport, Error> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(UdpServerTransport {
+            socket,
+            clients: HashMap::new(),
+            addresses: HashMap::new(),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn accept(&mut self, address: SocketAddr) -> Option<ClientId> {
+        let client_id = (0..=ClientId::max_value()).find(|id| !self.clients.contains_key(id))?;
+        self.clients.insert(
+            client_id,
+            UdpClient {
+                address,
+                last_received: Instant::now(),
+                last_sent: Instant::now(),
+            },
+        );
+        self.addresses.insert(address, client_id);
+        Some(client_id)
+    }
+
+    fn drop_client(&mut self, client_id: ClientId) -> Option<UdpClient> {
+        let client = self.clients.remove(&client_id)?;
+        self.addresses.remove(&client.address);
+        Some(client)
+    }
+}
+
+impl Transport for UdpServerTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        let client = self
+            .clients
+            .get_mut(&connection)
+            .ok_or_else(|| format_err!("attempt to send to unknown client {}", connection))?;
+        self.socket
+            .send_to(&datagram(DATAGRAM_DATA, &data), client.address)?;
+        client.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        let mut events = Vec::new();
+        loop {
+            let (size, address) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // errors caused by a single client (such as an ICMP port unreachable) must not
+                // take down the server, that client will time out instead
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if size == 0 {
+                continue;
+            }
+
+            let kind = self.buffer[0];
+            let client_id = match self.addresses.get(&address).cloned() {
+                Some(client_id) => client_id,
+                None if kind == DATAGRAM_CONNECT => match self.accept(address) {
+                    Some(client_id) => {
+                        events.push(TransportEvent::Connected(client_id));
+                        client_id
+                    }
+                    // server is full, the client will time out
+                    None => continue,
+                },
+                // data from an address that never connected
+                None => continue,
+            };
+
+            if let Some(client) = self.clients.get_mut(&client_id) {
+                client.last_received = Instant::now();
+            }
+
+            if kind == DATAGRAM_CONNECT {
+                // (re)acknowledge the connection, the client retries until it hears back
+                let ack = datagram(DATAGRAM_CONNECT, &[]);
+                if self.socket.send_to(&ack, address).is_err() {
+                    self.drop_client(client_id);
+                    events.push(TransportEvent::Disconnected(client_id));
+                }
+            } else if kind == DATAGRAM_DATA {
+                events.push(TransportEvent::Received(
+                    client_id,
+                    self.buffer[1..size].to_vec(),
+                ));
+            } else if kind == DATAGRAM_DISCONNECT {
+                self.drop_client(client_id);
+                events.push(TransportEvent::Disconnected(client_id));
+            }
+        }
+
+        let timeout = Duration::from_millis(TIMEOUT_MS);
+        let timed_out: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|&(_, c)| c.last_received.elapsed() > timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in timed_out {
+            self.drop_client(client_id);
+            events.push(TransportEvent::Disconnected(client_id));
+        }
+
+        // a client we can't send to is dropped, the others are unaffected
+        let heartbeat_interval = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
+        let mut unreachable = Vec::new();
+        for (client_id, client) in self.clients.iter_mut() {
+            if client.last_sent.elapsed() > heartbeat_interval {
+                let heartbeat = datagram(DATAGRAM_HEARTBEAT, &[]);
+                if self.socket.send_to(&heartbeat, client.address).is_err() {
+                    unreachable.push(*client_id);
+                }
+                client.last_sent = Instant::now();
+            }
+        }
+        for client_id in unreachable {
+            self.drop_client(client_id);
+            events.push(TransportEvent::Disconnected(client_id));
+        }
+
+        Ok(events)
+    }
+
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error> {
+        if let Some(client) = self.drop_client(connection) {
+            self.socket
+                .send_to(&datagram(DATAGRAM_DISCONNECT, &[]), client.address)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Client side UDP transport, connects to a single server.
+pub struct UdpClientTransport {
+    socket: UdpSocket,
+    connected: bool,
+    last_sent_connect: Option<Instant>,
+    last_received: Instant,
+    last_sent: Instant,
+    buffer: Vec<u8>,
+}
+
+impl UdpClientTransport {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<UdpClientTransport, Error> {
+        let server_address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format_err!("could not resolve server address"))?;
+        let local_address: SocketAddr = if server_address.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+
+        let socket = UdpSocket::bind(local_address)?;
+        socket.connect(server_address)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(UdpClientTransport {
+            socket,
+            connected: false,
+            last_sent_connect: None,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+}
+
+impl Transport for UdpClientTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        if connection != SERVER_CONNECTION {
+            return Err(format_err!("client can only send to the server"));
+        }
+        if !self.connected {
+            return Err(format_err!("not connected"));
+        }
+        self.socket.send(&datagram(DATAGRAM_DATA, &data))?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        let mut events = Vec::new();
+
+        let retry_connect = self
+            .last_sent_connect
+            .map(|t| t.elapsed() > Duration::from_millis(CONNECT_RETRY_INTERVAL_MS))
+            .unwrap_or(true);
+        if !self.connected && retry_connect {
+            match self.socket.send(&datagram(DATAGRAM_CONNECT, &[])) {
+                Ok(_) => {}
+                // the server isn't up (yet), keep retrying until the timeout
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.last_sent_connect = Some(Instant::now());
+        }
+
+        loop {
+            let size = match self.socket.recv(&mut self.buffer) {
+                Ok(size) => size,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => break,
+                Err(e) => return Err(e.into()),
+            };
+            if size == 0 {
+                continue;
+            }
+            self.last_received = Instant::now();
+
+            let kind = self.buffer[0];
+            if kind == DATAGRAM_CONNECT {
+                if !self.connected {
+                    self.connected = true;
+                    events.push(TransportEvent::Connected(SERVER_CONNECTION));
+                }
+            } else if kind == DATAGRAM_DATA && self.connected {
+                events.push(TransportEvent::Received(
+                    SERVER_CONNECTION,
+                    self.buffer[1..size].to_vec(),
+                ));
+            } else if kind == DATAGRAM_DISCONNECT && self.connected {
+                self.connected = false;
+                events.push(TransportEvent::Disconnected(SERVER_CONNECTION));
+                return Ok(events);
+            }
+        }
+
+        if self.last_received.elapsed() > Duration::from_millis(TIMEOUT_MS) {
+            if self.connected {
+                self.connected = false;
+                events.push(TransportEvent::Disconnected(SERVER_CONNECTION));
+            } else {
+                return Err(format_err!("timed out connecting to server"));
+            }
+        }
+
+        if self.connected && self.last_sent.elapsed() > Duration::from_millis(HEARTBEAT_INTERVAL_MS)
+        {
+            self.socket.send(&datagram(DATAGRAM_HEARTBEAT, &[]))?;
+            self.last_sent = Instant::now();
+        }
+
+        Ok(events)
+    }
+
+    fn disconnect(&mut self, _: ClientId) -> Result<(), Error> {
+        if self.connected {
+            self.connected = false;
+            self.socket.send(&datagram(DATAGRAM_DISCONNECT, &[]))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UdpClientTransport {
+    fn drop(&mut self) {
+        let _ = self.disconnect(SERVER_CONNECTION);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::{check_conformance, poll_until};
+
+    fn connected_pair() -> (UdpServerTransport, UdpClientTransport, ClientId) {
+        let mut server = UdpServerTransport::bind("127.0.0.1:0").unwrap();
+        let mut client = UdpClientTransport::connect(server.local_addr().unwrap()).unwrap();
+
+        let (server_events, client_events) = poll_until(&mut server, &mut client, |s, c| {
+            !s.is_empty() && !c.is_empty()
+        });
+        let client_id = match server_events[..] {
+            [TransportEvent::Connected(client_id)] => client_id,
+            _ => panic!("expected the server to see the client connect"),
+        };
+        match client_events[..] {
+            [TransportEvent::Connected(id)] if id == SERVER_CONNECTION => {}
+            _ => panic!("expected the client to connect to the server"),
+        }
+        (server, client, client_id)
+    }
+
+    #[test]
+    fn conforms_to_the_transport_contract() {
+        check_conformance(connected_pair);
+    }
+}
@@ -0,0 +1,349 @@
+use failure::Error;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use tungstenite;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Message, WebSocket};
+
+use net::ClientId;
+use transport::{Transport, TransportEvent, SERVER_CONNECTION};
+
+type ServerMidHandshake = MidHandshake<ServerHandshake<TcpStream, NoCallback>>;
+type ServerHandshakeResult =
+    Result<WebSocket<TcpStream>, HandshakeError<ServerHandshake<TcpStream, NoCallback>>>;
+
+// connections that haven't completed the handshake in this long are dropped, so they can't hold
+// on to client ids
+static HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+enum Connection {
+    // with the time the connection was accepted
+    Handshaking(ServerMidHandshake, Instant),
+    Open(WebSocket<TcpStream>),
+}
+
+enum ReadResult {
+    Received(Vec<u8>),
+    Idle,
+    Closed,
+}
+
+/// Reads the next binary message without blocking. Non-binary messages are skipped, pings are
+/// answered by tungstenite itself.
+fn read_message(socket: &mut WebSocket<TcpStream>) -> ReadResult {
+    loop {
+        match socket.read_message() {
+            Ok(Message::Binary(data)) => return ReadResult::Received(data),
+            Ok(Message::Close(_)) => return ReadResult::Closed,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                return ReadResult::Idle
+            }
+            Err(_) => return ReadResult::Closed,
+        }
+    }
+}
+
+/// Writes a binary message without blocking, returns false if the connection is gone.
+fn write_message(socket: &mut WebSocket<TcpStream>, data: Vec<u8>) -> bool {
+    match socket.write_message(Message::Binary(data)) {
+        Ok(_) => true,
+        // the message is queued and will be flushed on a later poll
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => true,
+        Err(_) => false,
+    }
+}
+
+fn write_pending(socket: &mut WebSocket<TcpStream>) -> bool {
+    match socket.write_pending() {
+        Ok(_) => true,
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => true,
+        Err(_) => false,
+    }
+}
+
+/// Server side WebSocket transport, lets browser clients connect to a native server. Every
+/// packet is sent as a single binary message.
+pub struct WebSocketServerTransport {
+    listener: TcpListener,
+    connections: HashMap<ClientId, Connection>,
+    handshake_timeout: Duration,
+}
+
+impl WebSocketServerTransport {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<WebSocketServerTransport, Error> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(WebSocketServerTransport {
+            listener,
+            connections: HashMap::new(),
+            handshake_timeout: Duration::from_millis(HANDSHAKE_TIMEOUT_MS),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    fn free_client_id(&self) -> Option<ClientId> {
+        (0..=ClientId::max_value()).find(|id| !self.connections.contains_key(id))
+    }
+
+    /// Continues a handshake started at the given time, returns true once the connection is
+    /// open.
+    fn handshake(
+        &mut self,
+        client_id: ClientId,
+        handshake: ServerHandshakeResult,
+        started: Instant,
+    ) -> bool {
+        match handshake {
+            Ok(socket) => {
+                self.connections.insert(client_id, Connection::Open(socket));
+                true
+            }
+            Err(HandshakeError::Interrupted(mid)) => {
+                self.connections
+                    .insert(client_id, Connection::Handshaking(mid, started));
+                false
+            }
+            Err(HandshakeError::Failure(_)) => false,
+        }
+    }
+}
+
+impl Transport for WebSocketServerTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        let sent = match self.connections.get_mut(&connection) {
+            Some(&mut Connection::Open(ref mut socket)) => write_message(socket, data),
+            _ => return Err(format_err!("attempt to send to unknown client {}", connection)),
+        };
+        // a broken connection is dropped on the next poll
+        if !sent {
+            if let Some(&mut Connection::Open(ref mut socket)) =
+                self.connections.get_mut(&connection)
+            {
+                let _ = socket.close(None);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        let mut events = Vec::new();
+
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // failing to accept a connection (it was aborted, or we're out of file
+                // descriptors) is no reason to stop serving the others, try again next poll
+                Err(_) => break,
+            };
+            let client_id = match self.free_client_id() {
+                Some(client_id) => client_id,
+                // server is full, drop the connection
+                None => continue,
+            };
+            if stream.set_nonblocking(true).is_err() || stream.set_nodelay(true).is_err() {
+                continue;
+            }
+
+            if self.handshake(client_id, tungstenite::accept(stream), Instant::now()) {
+                events.push(TransportEvent::Connected(client_id));
+            }
+        }
+
+        let client_ids: Vec<ClientId> = self.connections.keys().cloned().collect();
+        for client_id in client_ids {
+            match self.connections.remove(&client_id) {
+                Some(Connection::Handshaking(mid, started)) => {
+                    // never reported as connected, so it's dropped silently
+                    if started.elapsed() > self.handshake_timeout {
+                        continue;
+                    }
+                    if self.handshake(client_id, mid.handshake(), started) {
+                        events.push(TransportEvent::Connected(client_id));
+                    }
+                }
+                Some(Connection::Open(mut socket)) => {
+                    let mut open = write_pending(&mut socket);
+                    while open {
+                        match read_message(&mut socket) {
+                            ReadResult::Received(data) => {
+                                events.push(TransportEvent::Received(client_id, data))
+                            }
+                            ReadResult::Idle => break,
+                            ReadResult::Closed => open = false,
+                        }
+                    }
+
+                    if open {
+                        self.connections.insert(client_id, Connection::Open(socket));
+                    } else {
+                        events.push(TransportEvent::Disconnected(client_id));
+                    }
+                }
+                None => {}
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn disconnect(&mut self, connection: ClientId) -> Result<(), Error> {
+        if let Some(Connection::Open(mut socket)) = self.connections.remove(&connection) {
+            let _ = socket.close(None);
+            let _ = write_pending(&mut socket);
+        }
+
+        Ok(())
+    }
+}
+
+/// Native client side WebSocket transport, mostly useful for testing a server without a
+/// browser.
+pub struct WebSocketClientTransport {
+    socket: Option<WebSocket<TcpStream>>,
+    connected: bool,
+}
+
+impl WebSocketClientTransport {
+    pub fn connect(address: &str) -> Result<WebSocketClientTransport, Error> {
+        // the handshake is done blocking, the socket is only made non-blocking once it's open
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let (socket, _) = tungstenite::client(format!("ws://{}/", address), stream)
+            .map_err(|e| format_err!("websocket handshake failed: {}", e))?;
+        socket.get_ref().set_nonblocking(true)?;
+
+        Ok(WebSocketClientTransport {
+            socket: Some(socket),
+            connected: false,
+        })
+    }
+}
+
+impl Transport for WebSocketClientTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        if connection != SERVER_CONNECTION {
+            return Err(format_err!("client can only send to the server"));
+        }
+        match self.socket {
+            Some(ref mut socket) => {
+                if !write_message(socket, data) {
+                    return Err(format_err!("connection to server lost"));
+                }
+            }
+            None => return Err(format_err!("not connected")),
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        let mut events = Vec::new();
+
+        let mut closed = false;
+        if let Some(ref mut socket) = self.socket {
+            if !self.connected {
+                self.connected = true;
+                events.push(TransportEvent::Connected(SERVER_CONNECTION));
+            }
+
+            closed = !write_pending(socket);
+            while !closed {
+                match read_message(socket) {
+                    ReadResult::Received(data) => {
+                        events.push(TransportEvent::Received(SERVER_CONNECTION, data))
+                    }
+                    ReadResult::Idle => break,
+                    ReadResult::Closed => closed = true,
+                }
+            }
+        }
+
+        if closed {
+            self.socket = None;
+            events.push(TransportEvent::Disconnected(SERVER_CONNECTION));
+        }
+
+        Ok(events)
+    }
+
+    fn disconnect(&mut self, _: ClientId) -> Result<(), Error> {
+        if let Some(mut socket) = self.socket.take() {
+            let _ = socket.close(None);
+            let _ = write_pending(&mut socket);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use transport::{check_conformance, poll_until};
+
+    fn connected_pair() -> (WebSocketServerTransport, WebSocketClientTransport, ClientId) {
+        let mut server = WebSocketServerTransport::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+
+        // the client handshakes blocking, so the server has to be polled meanwhile
+        let connecting = thread::spawn(move || WebSocketClientTransport::connect(&address));
+        let mut server_events = Vec::new();
+        for _ in 0..2000 {
+            server_events.extend(server.poll().unwrap());
+            if !server_events.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut client = connecting.join().unwrap().unwrap();
+        let client_id = match server_events[..] {
+            [TransportEvent::Connected(client_id)] => client_id,
+            _ => panic!("expected the server to see the client connect"),
+        };
+
+        let (_, client_events) = poll_until(&mut server, &mut client, |_, c| !c.is_empty());
+        match client_events[..] {
+            [TransportEvent::Connected(id)] if id == SERVER_CONNECTION => {}
+            _ => panic!("expected the client to connect to the server"),
+        }
+        (server, client, client_id)
+    }
+
+    #[test]
+    fn conforms_to_the_transport_contract() {
+        check_conformance(connected_pair);
+    }
+
+    #[test]
+    fn stalled_handshakes_time_out() {
+        let mut server = WebSocketServerTransport::bind("127.0.0.1:0").unwrap();
+        server.handshake_timeout = Duration::from_millis(50);
+
+        // connects but never sends a handshake
+        let _stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        for _ in 0..2000 {
+            assert!(server.poll().unwrap().is_empty());
+            if !server.connections.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(server.connections.len(), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(server.poll().unwrap().is_empty());
+        assert!(server.connections.is_empty());
+    }
+}
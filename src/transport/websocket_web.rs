@@ -0,0 +1,120 @@
+use failure::Error;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+use net::ClientId;
+use transport::{Transport, TransportEvent, SERVER_CONNECTION};
+
+/// Browser client side WebSocket transport, connects the wasm client to a native server running
+/// a `WebSocketServerTransport`.
+pub struct WebSocketClientTransport {
+    socket: WebSocket,
+    events: Rc<RefCell<VecDeque<TransportEvent>>>,
+    connected: Rc<RefCell<bool>>,
+
+    // the callbacks have to be kept alive for as long as the socket can call them
+    _on_open: Closure<FnMut(JsValue)>,
+    _on_message: Closure<FnMut(MessageEvent)>,
+    _on_close: Closure<FnMut(JsValue)>,
+}
+
+impl WebSocketClientTransport {
+    pub fn connect(address: &str) -> Result<WebSocketClientTransport, Error> {
+        let socket = WebSocket::new(&format!("ws://{}/", address))
+            .map_err(|e| format_err!("failed to open websocket: {:?}", e))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let events = Rc::new(RefCell::new(VecDeque::new()));
+        let connected = Rc::new(RefCell::new(false));
+
+        let on_open = {
+            let events = events.clone();
+            let connected = connected.clone();
+            Closure::wrap(Box::new(move |_: JsValue| {
+                *connected.borrow_mut() = true;
+                events
+                    .borrow_mut()
+                    .push_back(TransportEvent::Connected(SERVER_CONNECTION));
+            }) as Box<FnMut(JsValue)>)
+        };
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+
+        let on_message = {
+            let events = events.clone();
+            Closure::wrap(Box::new(move |event: MessageEvent| {
+                if let Ok(buffer) = event.data().dyn_into::<ArrayBuffer>() {
+                    let data = Uint8Array::new(&buffer).to_vec();
+                    events
+                        .borrow_mut()
+                        .push_back(TransportEvent::Received(SERVER_CONNECTION, data));
+                }
+            }) as Box<FnMut(MessageEvent)>)
+        };
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        let on_close = {
+            let events = events.clone();
+            let connected = connected.clone();
+            Closure::wrap(Box::new(move |_: JsValue| {
+                *connected.borrow_mut() = false;
+                events
+                    .borrow_mut()
+                    .push_back(TransportEvent::Disconnected(SERVER_CONNECTION));
+            }) as Box<FnMut(JsValue)>)
+        };
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(WebSocketClientTransport {
+            socket,
+            events,
+            connected,
+
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+}
+
+impl Transport for WebSocketClientTransport {
+    fn send(&mut self, connection: ClientId, data: Vec<u8>) -> Result<(), Error> {
+        if connection != SERVER_CONNECTION {
+            return Err(format_err!("client can only send to the server"));
+        }
+        if !*self.connected.borrow() {
+            return Err(format_err!("not connected"));
+        }
+        self.socket
+            .send_with_u8_array(&data)
+            .map_err(|e| format_err!("failed to send on websocket: {:?}", e))?;
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<TransportEvent>, Error> {
+        Ok(self.events.borrow_mut().drain(..).collect())
+    }
+
+    fn disconnect(&mut self, _: ClientId) -> Result<(), Error> {
+        self.socket
+            .close()
+            .map_err(|e| format_err!("failed to close websocket: {:?}", e))?;
+
+        Ok(())
+    }
+}
+
+impl Drop for WebSocketClientTransport {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}